@@ -1,4 +1,4 @@
-use clap::{Command, Arg, ArgMatches};
+use clap::{Command, Arg, ArgAction, ArgMatches};
 
 pub fn parse_cli() -> ArgMatches {
     let app = Command::new("DevSync")
@@ -15,6 +15,18 @@ pub fn parse_cli() -> ArgMatches {
             .short('f')
             .long("file")
         )
+        .arg(
+            Arg::new("format")
+            .long("format")
+            .help("Format of the config file: json, yaml or toml. Picked by extension when not given")
+        )
+        .arg(
+            Arg::new("profile")
+            .long("profile")
+            .action(ArgAction::Append)
+            .value_delimiter(',')
+            .help("Profile from the config to apply, several are applied in order")
+        )
         .arg(
             Arg::new("ip")
             .long("ip")
@@ -23,6 +35,18 @@ pub fn parse_cli() -> ArgMatches {
             Arg::new("port")
             .short('p')
             .long("port")
+        )
+        .subcommand(
+            Command::new("schema")
+            .about("Prints the JSON schema of the config file, as kept in setup.schema.json")
+        )
+        .subcommand(
+            Command::new("convert")
+            .about("Rewrites a config file in another format")
+            .arg(Arg::new("input").required(true))
+            .arg(Arg::new("output").help("Written to stdout when not given"))
+            .arg(Arg::new("from").long("from").help("Format of the input, picked by extension when not given"))
+            .arg(Arg::new("to").long("to").help("Format of the output, picked by extension when not given"))
         );
     return app.get_matches();
 }
@@ -0,0 +1,36 @@
+use std::{sync::{Arc, Mutex}, thread, time::Duration};
+
+use crate::structs::data::{GlobalState, Health, get_ro_from_mutex};
+
+/// Runs the active health checks of every service that declares one, each
+/// instance on its own interval. Services are re-read on every tick, so the
+/// loop follows whatever state is current.
+pub fn run_health_checks(global: Arc<Mutex<GlobalState>>) {
+    loop {
+        if let Some(global) = get_ro_from_mutex(&global) {
+            for (name, service) in global.services {
+                let interval = match service.health_check() {
+                    Some(check) => check.interval,
+                    None => continue
+                };
+                for instance in service.instances() {
+                    if !instance.start_check(interval) {
+                        continue;
+                    }
+                    let (name, service, instance) = (name.clone(), service.clone(), Arc::clone(instance));
+                    thread::spawn(move || {
+                        let health = match service.check(&instance) {
+                            Ok(_) => Health::Healthy,
+                            Err(reason) => Health::Unhealthy(reason)
+                        };
+                        let report = health.to_string();
+                        if instance.finish_check(health) {
+                            println!("Service {} instance {} is {}", name, instance.address(), report);
+                        }
+                    });
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+}
@@ -1,42 +1,105 @@
-use crate::structs::{data::{Node, GlobalState, get_ro_from_mutex}, http::{Request, Response}};
+use crate::{structs::{connection::{Connection, Stream}, data::{Exposed, Node, GlobalState, get_ro_snapshot}, http::{Request, Response, split_query, get_header, has_valid_framing}}, tls};
 
-use std::{io::prelude::*, net::{TcpListener, TcpStream}, sync::{Arc, Mutex}, thread};
+use std::{io::{self, prelude::*}, net::{TcpListener, TcpStream}, sync::{Arc, Mutex}, thread, time::Duration};
 
-pub fn handle_request(mut stream:TcpStream, global: &GlobalState, tree: &Node) {
-    if let Some(mut req) = Request::from_stream(&stream) {
-        let (node, remained_uri) = tree.search(req.uri);
-        req.uri = remained_uri;
-        let res = match node.value.as_ref() {
-            Some(component) => {
-                let global_clone = (*global).clone();
-                component.call(&global_clone, req)
-            },
-            None => Response::not_found()
-        };
-        stream.write_all(res.into_string().as_bytes()).unwrap();
-    }
-    
+use rustls::ServerConfig;
+
+pub fn handle_request(req: Request, client: &mut Connection, global: &GlobalState, tree: &Node) -> io::Result<()> {
+    let mut req = req;
+    let (path, query) = split_query(&req.uri);
+    let (path, query) = (path.to_string(), query.to_string());
+    let tree = tree.for_host(get_header(&req.headers, "Host").map(|host| host.as_str()).unwrap_or_default());
+    let route = tree.search(path.clone(), &mut |endpoint| endpoint.allows_method(&req.method) && endpoint.matches_guards(&req));
+    return match route {
+        Some(route) => {
+            req.uri = match query.is_empty() {
+                true => route.remained,
+                false => format!("{}?{}", route.remained, query)
+            };
+            req.params = route.params;
+            route.component.serve(global, req, client)
+        },
+        None => {
+            let allowed = tree.allowed_methods(path, &req);
+            client.read_body(&mut req)?;
+            match allowed.is_empty() {
+                true => client.respond(Response::not_found()),
+                false => client.respond(Response::method_not_allowed(&allowed))
+            }
+        }
+    };
 }
 
-pub fn run_async_server(global: Arc<Mutex<GlobalState>>, tree: Arc<Mutex<Node>>) {
-    let pure_global = global.lock().unwrap();
-    if let Some(exposed) = &pure_global.exposed {
-        let server = TcpListener::bind(&exposed.stringify()).unwrap();
-        drop(pure_global);
-        for stream in server.incoming() {
-            let global = Arc::clone(&global);
-            let tree = Arc::clone(&tree);
-            thread::spawn(move || {
-                match stream {
-                    Ok(stream) => {
-                        let global_guard = get_ro_from_mutex(&global).unwrap();
-                        let tree_guard = get_ro_from_mutex(&tree).unwrap();
-                        handle_request(stream, &global_guard, &tree_guard);
-                    },
-                    Err(_) => println!("Error")
-                };
-            });
+/// Serves requests off one client connection until it asks to close, goes idle
+/// for longer than `idle_timeout` or breaks. Pipelined requests are answered in
+/// order. A request that cannot be parsed gets a 400 and ends the connection.
+pub fn handle_connection(stream: TcpStream, global: Arc<Mutex<GlobalState>>, tree: Arc<Mutex<Node>>, idle_timeout: Duration, tls_config: Option<Arc<ServerConfig>>) {
+    if stream.set_read_timeout(Some(idle_timeout)).is_err() {
+        return;
+    }
+    let stream: Box<dyn Stream> = match tls_config {
+        Some(tls_config) => match tls::accept(tls_config, stream) {
+            Ok(stream) => Box::new(stream),
+            Err(_) => return
+        },
+        None => Box::new(stream)
+    };
+    let mut client = Connection::new(stream, idle_timeout);
+    // Stops once the client closed the connection or went idle.
+    while client.reader.fill_buf().is_ok_and(|buf| !buf.is_empty()) {
+        let req = match Request::read_head(&mut client.reader) {
+            Some(req) if has_valid_framing(&req.headers) => req,
+            _ => {
+                client.keep_alive = false;
+                client.head_only = false;
+                let _ = client.respond(Response::bad_request());
+                break;
+            }
+        };
+        client.keep_alive = req.keep_alive();
+        client.head_only = req.method == "HEAD";
+        // Each request runs on its own snapshot, so a reload never changes one mid-flight.
+        let result = match get_ro_snapshot(&global, &tree) {
+            Some((global, tree)) => handle_request(req, &mut client, &global, &tree),
+            None => {
+                client.keep_alive = false;
+                client.respond(Response::server_error())
+            }
+        };
+        if result.is_err() || !client.keep_alive {
+            break;
         }
     }
 }
 
+/// The bound socket and TLS setup of `exposed`. Built at startup, so a busy port
+/// or a bad certificate stops DevSync before anything is served.
+pub struct Listener {
+    server: TcpListener,
+    idle_timeout: Duration,
+    tls_config: Option<Arc<ServerConfig>>
+}
+
+pub fn bind(exposed: &Exposed) -> Result<Listener, String> {
+    let tls_config = match &exposed.tls {
+        Some(tls) => Some(tls::server_config(tls).map_err(|err| format!("Could not set up TLS: {}", err))?),
+        None => None
+    };
+    let server = TcpListener::bind(exposed.stringify()).map_err(|err| format!("Could not listen on {}: {}", exposed.stringify(), err))?;
+    return Ok(Listener { server, idle_timeout: Duration::from_secs(exposed.idle_timeout), tls_config });
+}
+
+pub fn run_async_server(listener: Listener, global: Arc<Mutex<GlobalState>>, tree: Arc<Mutex<Node>>) {
+    for stream in listener.server.incoming() {
+        let global = Arc::clone(&global);
+        let tree = Arc::clone(&tree);
+        let tls_config = listener.tls_config.clone();
+        let idle_timeout = listener.idle_timeout;
+        thread::spawn(move || {
+            match stream {
+                Ok(stream) => handle_connection(stream, global, tree, idle_timeout, tls_config),
+                Err(_) => println!("Error")
+            };
+        });
+    }
+}
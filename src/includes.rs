@@ -0,0 +1,177 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use regex::escape;
+use serde_json::Value;
+
+use crate::{parsing::{ConfigFormat, read_config}, structs::http::merge_paths};
+
+/// Keys an included fragment may set; everything else belongs to the main config.
+const FRAGMENT_KEYS: [&str; 5] = ["$schema", "include", "services", "requests", "hosts"];
+
+/// The file every service, request and hosts entry came from, index by index,
+/// so problems found after merging can point at the right fragment.
+#[derive(Default)]
+pub struct Origins {
+    pub services: Vec<String>,
+    pub requests: Vec<String>,
+    pub hosts: Vec<String>
+}
+
+fn list_len(config: &Value, key: &str) -> usize {
+    return config.get(key).and_then(|list| list.as_array()).map(|list| list.len()).unwrap_or(0);
+}
+
+impl Origins {
+    /// Marks every item of `config` past the ones already known as coming from `file`.
+    pub fn fill(&mut self, config: &Value, file: &str) {
+        for (key, list) in [("services", &mut self.services), ("requests", &mut self.requests), ("hosts", &mut self.hosts)] {
+            while list.len() < list_len(config, key) {
+                list.push(file.to_string());
+            }
+        }
+    }
+
+    fn append(&mut self, other: Origins) {
+        self.services.extend(other.services);
+        self.requests.extend(other.requests);
+        self.hosts.extend(other.hosts);
+    }
+}
+
+/// `{"path": ..., "prefix": ...}` or just the path.
+fn include_entry(entry: &Value) -> Result<(&str, Option<&str>), String> {
+    return match entry {
+        Value::String(path) => Ok((path, None)),
+        Value::Object(fields) => match fields.get("path").and_then(|path| path.as_str()) {
+            Some(path) => Ok((path, fields.get("prefix").and_then(|prefix| prefix.as_str()))),
+            None => Err("Every include must have a path".to_string())
+        },
+        _ => Err("Include must be a path or an object with path and prefix".to_string())
+    };
+}
+
+/// Files matched by an include pattern, relative to the directory of the file including them.
+fn expand(pattern: &str, including: &Path) -> Result<Vec<PathBuf>, String> {
+    let base = including.parent().unwrap_or(Path::new(""));
+    let full = base.join(pattern);
+    let paths = match glob::glob(&full.to_string_lossy()) {
+        Ok(paths) => paths,
+        Err(err) => return Err(format!("Invalid include {}: {}", pattern, err))
+    };
+    let paths: Vec<PathBuf> = paths.filter_map(|path| path.ok()).filter(|path| path.is_file()).collect();
+    if paths.is_empty() {
+        return Err(format!("Include {} matches no files", pattern));
+    }
+    return Ok(paths);
+}
+
+/// `prefix` put in front of a request's `uri` or `uri_regex`.
+fn mount(request: &mut Value, prefix: &str) {
+    if merge_paths(prefix, "").is_empty() {
+        return;
+    }
+    if let Some(Value::String(uri)) = request.get_mut("uri") {
+        let trailing = if uri.ends_with("/") { "/" } else { "" };
+        *uri = format!("/{}{}", merge_paths(prefix, uri), trailing);
+    }
+    if let Some(Value::String(uri_regex)) = request.get_mut("uri_regex") {
+        *uri_regex = format!("{}(?:{})", escape(&format!("/{}", merge_paths(prefix, ""))), uri_regex);
+    }
+}
+
+fn mount_all(fragment: &mut Value, prefix: &str) {
+    if let Some(Value::Array(requests)) = fragment.get_mut("requests") {
+        requests.iter_mut().for_each(|request| mount(request, prefix));
+    }
+    if let Some(Value::Array(hosts)) = fragment.get_mut("hosts") {
+        for host in hosts {
+            if let Some(Value::Array(requests)) = host.get_mut("requests") {
+                requests.iter_mut().for_each(|request| mount(request, prefix));
+            }
+        }
+    }
+}
+
+fn append_list(config: &mut Value, key: &str, items: Option<Value>) {
+    if let Some(Value::Array(items)) = items {
+        match config.get_mut(key) {
+            Some(Value::Array(list)) => list.extend(items),
+            _ => config[key] = Value::Array(items)
+        }
+    }
+}
+
+/// Replaces the `include` key of `config`, read from `file_path`, with the services,
+/// requests and hosts of the files it names, appended after its own. Fragments can
+/// include more fragments; `stack` holds the files being read to catch cycles.
+fn resolve(mut config: Value, file_path: &Path, stack: &mut Vec<PathBuf>) -> Result<(Value, Origins), String> {
+    let mut origins = Origins::default();
+    origins.fill(&config, &file_path.to_string_lossy());
+    let includes = match config.as_object_mut().and_then(|config| config.remove("include")) {
+        Some(Value::Array(includes)) => includes,
+        Some(_) => return Err(format!("{}: include must be an array", file_path.display())),
+        None => return Ok((config, origins))
+    };
+    for entry in &includes {
+        let (pattern, prefix) = include_entry(entry).map_err(|err| format!("{}: {}", file_path.display(), err))?;
+        for path in expand(pattern, file_path).map_err(|err| format!("{}: {}", file_path.display(), err))? {
+            let canonical = fs::canonicalize(&path).unwrap_or(path.clone());
+            if stack.contains(&canonical) {
+                return Err(format!("{}: including {} again makes a cycle", file_path.display(), path.display()));
+            }
+            let text = fs::read_to_string(&path).map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+            let fragment = read_config(&text, ConfigFormat::from_path(&path.to_string_lossy()))
+                .map_err(|err| format!("{}: {}", path.display(), err))?;
+            if let Some(key) = fragment.as_object().and_then(|fields| fields.keys().find(|key| !FRAGMENT_KEYS.contains(&key.as_str()))) {
+                return Err(format!("{}: {} can only be set in the main config", path.display(), key));
+            }
+            stack.push(canonical);
+            let (mut fragment, fragment_origins) = resolve(fragment, &path, stack)?;
+            stack.pop();
+            if let Some(prefix) = prefix {
+                mount_all(&mut fragment, prefix);
+            }
+            for key in ["services", "requests", "hosts"] {
+                append_list(&mut config, key, fragment.get_mut(key).map(Value::take));
+            }
+            origins.append(fragment_origins);
+        }
+    }
+    return Ok((config, origins));
+}
+
+/// Pulls every file named by `include` into `config`, recursively.
+pub fn resolve_includes(config: Value, file_path: &str) -> Result<(Value, Origins), String> {
+    let path = Path::new(file_path);
+    let mut stack = vec![fs::canonicalize(path).unwrap_or(path.to_path_buf())];
+    return resolve(config, path, &mut stack);
+}
+
+/// Every file included by the config at `file_path`, for the reload watcher.
+/// Files that cannot be read are left out, parsing reports them.
+pub fn included_files(file_path: &str, format: ConfigFormat) -> Vec<PathBuf> {
+    fn collect(file_path: &Path, format: ConfigFormat, files: &mut Vec<PathBuf>) {
+        let config = match fs::read_to_string(file_path).ok().and_then(|text| read_config(&text, format).ok()) {
+            Some(config) => config,
+            None => return
+        };
+        for entry in config.get("include").and_then(|includes| includes.as_array()).into_iter().flatten() {
+            let pattern = match include_entry(entry) {
+                Ok((pattern, _)) => pattern,
+                Err(_) => continue
+            };
+            for path in expand(pattern, file_path).unwrap_or_default() {
+                let canonical = fs::canonicalize(&path).unwrap_or(path.clone());
+                if !files.contains(&canonical) {
+                    files.push(canonical);
+                    collect(&path, ConfigFormat::from_path(&path.to_string_lossy()), files);
+                }
+            }
+        }
+    }
+    let path = Path::new(file_path);
+    let mut files = vec![fs::canonicalize(path).unwrap_or(path.to_path_buf())];
+    collect(path, format, &mut files);
+    files.remove(0);
+    return files;
+}
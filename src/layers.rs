@@ -0,0 +1,256 @@
+use std::{collections::HashMap, env, fs, path::{Path, PathBuf}, sync::LazyLock};
+
+use regex::{Captures, Regex};
+use serde_json::Value;
+
+use crate::{includes::{Origins, included_files, resolve_includes}, parsing::{ConfigFormat, read_config}};
+
+/// `setup.local.json` next to `setup.json`: a per-developer file merged over the shared one.
+pub fn local_override_path(file_path: &str) -> PathBuf {
+    let path = Path::new(file_path);
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+    let name = match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => format!("{}.local.{}", stem, extension),
+        None => format!("{}.local", stem)
+    };
+    return path.with_file_name(name);
+}
+
+/// The `.env` file in the config's directory.
+pub fn dotenv_path(file_path: &str) -> PathBuf {
+    return Path::new(file_path).with_file_name(".env");
+}
+
+/// Every file that goes into the config, for the reload watcher.
+pub fn layer_files(file_path: &str, format: ConfigFormat) -> Vec<PathBuf> {
+    let mut files = vec![PathBuf::from(file_path), local_override_path(file_path), dotenv_path(file_path)];
+    files.extend(included_files(file_path, format));
+    return files;
+}
+
+/// Reads `KEY=value` lines. Blank lines, `#` comments and a leading `export`
+/// are skipped, and matching quotes around a value are removed.
+pub fn parse_dotenv(text: &str) -> HashMap<String, String> {
+    let mut vars: HashMap<String, String> = HashMap::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("#") {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        if let Some((key, value)) = line.split_once("=") {
+            let value = value.trim();
+            let value = match (value.chars().next(), value.chars().last()) {
+                (Some(first), Some(last)) if value.len() >= 2 && first == last && (first == '"' || first == '\'') => &value[1..value.len() - 1],
+                _ => value
+            };
+            vars.insert(key.trim().to_string(), value.to_string());
+        }
+    }
+    return vars;
+}
+
+/// Variables for interpolation: the `.env` file, overridden by the real environment.
+pub fn load_env(file_path: &str) -> HashMap<String, String> {
+    let mut vars = match fs::read_to_string(dotenv_path(file_path)) {
+        Ok(text) => parse_dotenv(&text),
+        Err(_) => HashMap::new()
+    };
+    vars.extend(env::vars());
+    return vars;
+}
+
+/// Merges `over` into `base`. Objects merge key by key and `null` removes a key.
+/// Lists of services merge item by item on `name`, lists of requests on their
+/// route (`uri` or `uri_regex`, `methods` and `when`); any other array is replaced whole.
+/// A request given another `type` replaces the old one instead of merging into it.
+pub fn deep_merge(base: &mut Value, over: Value) {
+    match (base, over) {
+        (Value::Object(base), Value::Object(over)) => {
+            for (key, value) in over {
+                match (base.get_mut(&key), value) {
+                    (_, Value::Null) => { base.remove(&key); },
+                    (Some(existing), value) => deep_merge(existing, value),
+                    (None, value) => { base.insert(key, value); }
+                }
+            }
+        },
+        (Value::Array(base), Value::Array(over)) if identified(base) && identified(&over) => {
+            for item in over {
+                match base.iter_mut().find(|existing| identity(existing) == identity(&item)) {
+                    Some(existing) if item.get("type").is_some_and(|kind| existing.get("type") != Some(kind)) => *existing = item,
+                    Some(existing) => deep_merge(existing, item),
+                    None => base.push(item)
+                }
+            }
+        },
+        (base, over) => *base = over
+    }
+}
+
+/// What an array item is matched on when merging, if it is a service or a request.
+fn identity(item: &Value) -> Option<Value> {
+    if let Some(name) = item.get("name").filter(|name| name.is_string()) {
+        return Some(name.clone());
+    }
+    if item.get("uri").is_some() || item.get("uri_regex").is_some() {
+        return Some(route_key(item));
+    }
+    return None;
+}
+
+/// What makes two requests the same route: the same uri or uri_regex, methods and conditions.
+pub fn route_key(request: &Value) -> Value {
+    return serde_json::json!([request.get("uri"), request.get("uri_regex"), request.get("methods"), request.get("when")]);
+}
+
+fn identified(items: &[Value]) -> bool {
+    return items.iter().all(|item| identity(item).is_some());
+}
+
+/// A `${VAR}` or `${VAR:-default}` placeholder, or its `$${` escape.
+static PLACEHOLDER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\$(\$)?\{([A-Za-z_][A-Za-z0-9_]*)(?::-([^}]*))?\}").unwrap());
+
+/// Replaces `${VAR}` and `${VAR:-default}` in every string, `$${` being a literal
+/// `${`. Values stay strings; `Schema::coerce` turns the ones in number and
+/// boolean fields into their type afterwards.
+pub fn interpolate(value: &mut Value, vars: &HashMap<String, String>, pointer: &str, errors: &mut Vec<String>) {
+    match value {
+        Value::String(text) => {
+            if !text.contains("${") {
+                return;
+            }
+            let expanded = PLACEHOLDER.replace_all(text, |caps: &Captures| {
+                if caps.get(1).is_some() {
+                    return caps[0][1..].to_string();
+                }
+                let name = &caps[2];
+                match (vars.get(name).filter(|value| !value.is_empty()), caps.get(3)) {
+                    (Some(value), _) => value.to_string(),
+                    (None, Some(default)) => default.as_str().to_string(),
+                    (None, None) => {
+                        errors.push(format!("  {}: environment variable {} is not set", pointer, name));
+                        String::new()
+                    }
+                }
+            }).to_string();
+            *value = Value::String(expanded);
+        },
+        Value::Array(items) => items.iter_mut().enumerate()
+            .for_each(|(index, item)| interpolate(item, vars, &format!("{}/{}", pointer, index), errors)),
+        Value::Object(fields) => fields.iter_mut()
+            .for_each(|(key, item)| interpolate(item, vars, &format!("{}/{}", pointer, key.replace("~", "~0").replace("/", "~1")), errors)),
+        _ => {}
+    }
+}
+
+/// Pulls in included files, merges the selected profiles and then the local
+/// override file over `config` and fills in the environment. Also tells which
+/// file each list item came from.
+pub fn apply_layers(config: Value, file_path: &str, format: ConfigFormat, profiles: &[String]) -> Result<(Value, Origins), String> {
+    let (mut config, mut origins) = resolve_includes(config, file_path)?;
+    let mut declared = config.as_object_mut().and_then(|config| config.remove("profiles")).unwrap_or(Value::Object(Default::default()));
+    let local_path = local_override_path(file_path);
+    let local = match fs::read_to_string(&local_path) {
+        Ok(text) => Some(read_config(&text, format).map_err(|err| format!("{}: {}", local_path.display(), err))?),
+        Err(_) => None
+    };
+    // The local file can tweak profiles too, so they are merged before being applied.
+    if let Some(local_profiles) = local.as_ref().and_then(|local| local.get("profiles")) {
+        deep_merge(&mut declared, local_profiles.clone());
+    }
+    for name in profiles {
+        match declared.get(name) {
+            Some(profile) => deep_merge(&mut config, profile.clone()),
+            None => {
+                let names: Vec<&String> = declared.as_object().map(|declared| declared.keys().collect()).unwrap_or_default();
+                return Err(format!("Unknown profile {}, the config declares {}", name,
+                    if names.is_empty() { "none".to_string() } else { names.iter().map(|name| name.as_str()).collect::<Vec<&str>>().join(", ") }));
+            }
+        }
+        origins.fill(&config, &format!("{} profile {}", file_path, name));
+    }
+    if let Some(mut local) = local {
+        if let Some(local) = local.as_object_mut() {
+            local.remove("profiles");
+        }
+        deep_merge(&mut config, local);
+        origins.fill(&config, &local_path.to_string_lossy());
+    }
+    let mut errors: Vec<String> = Vec::new();
+    interpolate(&mut config, &load_env(file_path), "", &mut errors);
+    if !errors.is_empty() {
+        return Err(format!("Config has {} error(s):\n{}", errors.len(), errors.join("\n")));
+    }
+    return Ok((config, origins));
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        return pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+    }
+
+    #[test]
+    fn parse_dotenv_skips_comments_and_unquotes() {
+        let parsed = parse_dotenv("# comment\n\nexport API_PORT=3001\nNAME = \"dev services\"\nQUOTE='it''s'\nEMPTY=\nbroken line\n");
+        assert_eq!(parsed, vars(&[("API_PORT", "3001"), ("NAME", "dev services"), ("QUOTE", "it''s"), ("EMPTY", "")]));
+    }
+
+    #[test]
+    fn interpolate_fills_defaults_and_keeps_strings() {
+        let mut config = json!({
+            "exposed": {"port": "${API_PORT:-3001}"},
+            "headers": {"X-Api-Version": "${API_VERSION:-2}", "X-Host": "${HOST}:${API_PORT:-80}"},
+            "literal": "$${HOST}"
+        });
+        let mut errors: Vec<String> = Vec::new();
+        interpolate(&mut config, &vars(&[("HOST", "localhost"), ("API_PORT", "")]), "", &mut errors);
+        assert!(errors.is_empty());
+        assert_eq!(config, json!({
+            "exposed": {"port": "3001"},
+            "headers": {"X-Api-Version": "2", "X-Host": "localhost:80"},
+            "literal": "${HOST}"
+        }));
+    }
+
+    #[test]
+    fn interpolate_reports_unset_variables() {
+        let mut config = json!({"services": [{"name": "api", "address": "${API_HOST}"}]});
+        let mut errors: Vec<String> = Vec::new();
+        interpolate(&mut config, &HashMap::new(), "", &mut errors);
+        assert_eq!(errors, vec!["  /services/0/address: environment variable API_HOST is not set".to_string()]);
+    }
+
+    #[test]
+    fn deep_merge_merges_objects_and_lists_by_identity() {
+        let mut base = json!({
+            "exposed": {"port": 8080, "host": "localhost"},
+            "services": [{"name": "api", "port": 3001}, {"name": "web", "port": 3000}],
+            "requests": [
+                {"type": "proxy", "uri": "/api/", "service": "api"},
+                {"type": "mock", "uri": "/health", "body": "ok"}
+            ],
+            "tags": ["a", "b"]
+        });
+        deep_merge(&mut base, json!({
+            "exposed": {"host": null},
+            "services": [{"name": "api", "port": 4001}, {"name": "worker", "port": 3002}],
+            "requests": [{"type": "static", "uri": "/health", "path": "./health"}],
+            "tags": ["c"]
+        }));
+        assert_eq!(base, json!({
+            "exposed": {"port": 8080},
+            "services": [{"name": "api", "port": 4001}, {"name": "web", "port": 3000}, {"name": "worker", "port": 3002}],
+            "requests": [
+                {"type": "proxy", "uri": "/api/", "service": "api"},
+                {"type": "static", "uri": "/health", "path": "./health"}
+            ],
+            "tags": ["c"]
+        }));
+    }
+}
@@ -1,47 +1,116 @@
+#![allow(clippy::needless_return)]
+
 mod parsing;
 mod http;
 mod structs;
 mod runtime;
 mod networking;
 mod cli;
+mod tls;
+mod health;
+mod supervisor;
+mod reload;
+mod schema;
+mod layers;
+mod includes;
 
 use structs::data::{GlobalState, Node};
 use std::{thread, sync::{Mutex, Arc}};
 
-use parsing::parse_config;
-use http::run_async_server;
+use parsing::{ConfigFormat, parse_config, config_schema, convert_config};
+use http::{bind, run_async_server};
 use cli::parse_cli;
+use health::run_health_checks;
+use supervisor::{run_services, stop_all_services};
+use reload::watch_config;
 
-fn parse_into_arc_mutex(file_name: &str) -> (Arc<Mutex<Node>>, Arc<Mutex<GlobalState>>) {
-    match parse_config(file_name) {
+fn parse_into_arc_mutex(file_name: &str, format: ConfigFormat, profiles: &[String]) -> (Arc<Mutex<Node>>, Arc<Mutex<GlobalState>>) {
+    match parse_config(file_name, format, profiles) {
         Ok((tree, global)) => (
             Arc::new(Mutex::new(tree)),
             Arc::new(Mutex::new(global))
             ),
-        Err(err) => panic!("{}", err)
+        Err(err) => exit_with(&format!("Could not load {}: {}", file_name, err))
     }
 }
 
+/// Reports a problem the user has to fix and stops, without a backtrace.
+fn exit_with(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
 fn main() {
+    let m = parse_cli();
+
+    if m.subcommand_matches("schema").is_some() {
+        println!("{}", serde_json::to_string_pretty(&config_schema().to_json_schema_document()).unwrap());
+        return;
+    }
+    if let Some(convert) = m.subcommand_matches("convert") {
+        let input = convert.get_one::<String>("input").unwrap();
+        let output = convert.get_one::<String>("output");
+        let from = convert.get_one::<String>("from").map(|from| ConfigFormat::from_name(from)).unwrap_or(Ok(ConfigFormat::from_path(input)));
+        let to = match (convert.get_one::<String>("to"), output) {
+            (Some(to), _) => ConfigFormat::from_name(to),
+            (None, Some(output)) => Ok(ConfigFormat::from_path(output)),
+            (None, None) => Err("Give --to when writing to stdout".to_string())
+        };
+        let result = match (from, to) {
+            (Ok(from), Ok(to)) => convert_config(input, from, output.map(|output| output.as_str()), to),
+            (Err(err), _) | (_, Err(err)) => Err(err)
+        };
+        if let Err(err) = result {
+            exit_with(&err);
+        }
+        return;
+    }
+
     let version = "0.0.1".to_string();
     println!("DevSync v{}", version);
 
-    let m = parse_cli();
-
     let file_name = m.get_one::<String>("file");
     
     let (mut tree, mut global) = (
         Arc::new(Mutex::new(Node::new())),
         Arc::new(Mutex::new(GlobalState::blank()))
     );
+    let mut listener = None;
     if let Some(file_name) = file_name {
-        (tree, global) = parse_into_arc_mutex(&file_name);
+        let format = match m.get_one::<String>("format") {
+            Some(format) => ConfigFormat::from_name(format).unwrap_or_else(|err| exit_with(&err)),
+            None => ConfigFormat::from_path(file_name)
+        };
+        let profiles: Vec<String> = m.get_many::<String>("profile").map(|profiles| profiles.cloned().collect()).unwrap_or_default();
+        (tree, global) = parse_into_arc_mutex(file_name, format, &profiles);
+        if !profiles.is_empty() {
+            println!("Using profile {}", profiles.join(", "));
+        }
+        listener = global.lock().unwrap().exposed.as_ref().map(|exposed| bind(exposed).unwrap_or_else(|err| exit_with(&err)));
+        // Service processes lead their own process groups, so Ctrl-C no longer reaches them directly.
+        ctrlc::set_handler(|| {
+            stop_all_services();
+            std::process::exit(0);
+        }).unwrap_or_else(|err| exit_with(&format!("Could not handle Ctrl-C: {}", err)));
+        run_services(&global.lock().unwrap());
+        let file_name = file_name.to_string();
+        let tree = Arc::clone(&tree);
+        let global = Arc::clone(&global);
+        thread::spawn(move || {
+            watch_config(file_name, format, profiles, tree, global);
+        });
     }
-    {
+    if let Some(listener) = listener {
         let tree = Arc::clone(&tree);
         let global = Arc::clone(&global);
         thread::spawn(move || {
-            run_async_server(global, tree);
+            run_async_server(listener, global, tree);
+        });
+    }
+    {
+        let global = Arc::clone(&global);
+        thread::spawn(move || {
+            run_health_checks(global);
         });
     }
     runtime::setup(tree, global, &m);
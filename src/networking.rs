@@ -74,14 +74,16 @@ impl Message {
 
     pub fn send(&self, receiver: &NetworkNode) {
         let mut stream = TcpStream::connect(format!("{}:{}", receiver.ip, receiver.port)).unwrap();
-        stream.write(self.as_string().as_bytes()).unwrap();
+        stream.write_all(self.as_string().as_bytes()).unwrap();
     }
 }
 
+pub type Handler = fn(Message, Arc<Mutex<Node>>, Arc<Mutex<GlobalState>>);
+
 pub struct Network {
     nodes: HashMap<String, NetworkNode>,
     my_id: String,
-    handlers: HashMap<String, fn(Message, Arc<Mutex<Node>>, Arc<Mutex<GlobalState>>)>
+    handlers: HashMap<String, Handler>
 }
 
 #[derive(Debug)]
@@ -109,7 +111,7 @@ impl Network {
         let my_node = self.get_my_node();
         println!("Connecting to {}:{}...", ip, port);
         let mut stream = TcpStream::connect(format!("{}:{}", ip, port)).unwrap();
-        stream.write(
+        stream.write_all(
             format!("{} connect\r\nip: {}\r\nport: {}\r\n\r\n",
                     self.my_id,
                     my_node.ip,
@@ -118,7 +120,7 @@ impl Network {
         
     }
 
-    pub fn add_handler(&mut self, method: String, handler: fn(Message, Arc<Mutex<Node>>, Arc<Mutex<GlobalState>>)) {
+    pub fn add_handler(&mut self, method: String, handler: Handler) {
         self.handlers.insert(method, handler);
     }
 
@@ -1,8 +1,48 @@
-use std::{collections::HashMap, fs};
+use std::{collections::HashMap, fs, path::Path, sync::LazyLock};
 
+use regex::Regex;
 use uuid::Uuid;
 
-use crate::structs::{data::*, components::*};
+use crate::{includes::Origins, layers::{apply_layers, route_key}, schema::{Field, Schema, ValidationError}, structs::{data::*, components::*}};
+
+/// Schema of the whole config file, built from the config types themselves.
+pub fn config_schema() -> Schema {
+    let requests = || Schema::Ref("requests", || Schema::array(request_schema()));
+    return Schema::Object(vec![
+        Field::new("$schema", Schema::String),
+        Field::new("include", Schema::array(Schema::OneOf(vec![
+            Schema::String,
+            Schema::Object(vec![Field::required("path", Schema::String), Field::new("prefix", Schema::String)])
+        ]))),
+        Field::new("exposed", Exposed::schema()),
+        // Profiles only hold the parts they change, the merged result is what gets checked.
+        Field::new("profiles", Schema::map(Schema::Object(vec![
+            Field::new("exposed", Schema::map(Schema::Any)),
+            Field::new("services", Schema::array(Schema::map(Schema::Any))),
+            Field::new("requests", Schema::array(Schema::map(Schema::Any))),
+            Field::new("hosts", Schema::array(Schema::map(Schema::Any)))
+        ]))),
+        Field::new("services", Schema::array(Service::schema())),
+        Field::new("requests", requests()),
+        Field::new("hosts", Schema::array(Schema::Object(vec![
+            Field::required("host", Schema::OneOf(vec![Schema::String, Schema::array(Schema::String)])),
+            Field::new("requests", requests())
+        ])))
+    ]);
+}
+
+/// Checks a config against `config_schema`, listing every problem with its JSON pointer.
+pub fn validate_config(config:&serde_json::Value) -> Result<(), String> {
+    let mut errors: Vec<ValidationError> = Vec::new();
+    config_schema().validate(config, "", &mut errors);
+    if errors.is_empty() {
+        return Ok(());
+    }
+    let errors: Vec<String> = errors.iter()
+        .map(|error| format!("  {}: {}", if error.pointer.is_empty() { "/" } else { &error.pointer }, error.message))
+        .collect();
+    Err(format!("Config has {} error(s):\n{}", errors.len(), errors.join("\n")))
+}
 
 pub fn parse_root(component:serde_json::Value, global:&mut GlobalState) -> Result<(), String> {
     if let Some(exposed) = component.get("exposed") {
@@ -14,49 +54,233 @@ pub fn parse_root(component:serde_json::Value, global:&mut GlobalState) -> Resul
     Ok(())
 }
 
-pub fn parse_requests(comp:serde_json::Value, global:&GlobalState) -> Result<Node, String> {
+/// Services and requests can be switched off with `"enabled": false`, usually from a profile.
+pub fn is_enabled(comp:&serde_json::Value) -> bool {
+    comp.get("enabled") != Some(&serde_json::Value::Bool(false))
+}
+
+/// Parses every request, collecting the errors of all of them rather than stopping at the first.
+pub fn parse_requests(comp:serde_json::Value, global:&GlobalState) -> Result<Node, Vec<ConfigError>> {
     let mut res = Node::new();
+    let mut errors: Vec<ConfigError> = Vec::new();
     if let Some(requests) = comp.as_array() {
-        for request in requests {
-            let (uri, comp) = parse_request(request.clone(), global)?;
-            res.insert(uri, comp);
+        for (index, request) in requests.iter().enumerate().filter(|(_, request)| is_enabled(request)) {
+            match parse_request(request.clone(), global) {
+                Ok((path, comp)) => res.insert_route(path, comp),
+                Err(err) => errors.push(err.at(index))
+            }
         }
     }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
     Ok(res)
 }
 
-pub fn parse_request(comp:serde_json::Value, global:&GlobalState) -> Result<(String, Box<dyn Component>), String> {
-    let uri: String = match comp.get("uri") {
-        Some(uri) => match uri.as_str() {
-            Some(uri) => uri.to_string(),
-            None => return Err("Uri must be string".to_string())
+/// One line per error, pointing at the field, for requests listed under `pointer`.
+fn report_request_errors(errors: &[ConfigError], pointer: &str) -> Vec<String> {
+    return errors.iter()
+        .map(|error| format!("  {}/{}/{}: {}", pointer, error.index(), error.field(), error))
+        .collect();
+}
+
+pub fn parse_methods(comp:&serde_json::Value) -> Result<Vec<String>, String> {
+    let mut methods: Vec<String> = Vec::new();
+    if let Some(list) = comp.get("methods") {
+        let list = match list.as_array() {
+            Some(list) => list,
+            None => return Err("Methods must be an array".to_string())
+        };
+        for method in list {
+            match method.as_str() {
+                Some(method) => methods.push(method.to_ascii_uppercase()),
+                None => return Err("Every method must be string".to_string())
+            }
+        }
+    }
+    Ok(methods)
+}
+
+pub fn parse_guards(comp:&serde_json::Value) -> Result<Vec<Guard>, String> {
+    let mut guards: Vec<Guard> = Vec::new();
+    if let Some(list) = comp.get("when") {
+        let list = match list.as_array() {
+            Some(list) => list,
+            None => return Err("When must be an array of conditions".to_string())
+        };
+        for guard in list {
+            guards.push(Guard::from_json(guard.clone())?);
+        }
+    }
+    Ok(guards)
+}
+
+pub fn parse_request(comp:serde_json::Value, global:&GlobalState) -> Result<(RoutePath, Endpoint), ConfigError> {
+    let path = match (comp.get("uri"), comp.get("uri_regex")) {
+        (Some(_), Some(_)) => return Err(ConfigError::invalid("uri_regex", "cannot be used together with uri")),
+        (Some(uri), None) => match uri.as_str() {
+            Some(uri) => RoutePath::Uri(uri.to_string()),
+            None => return Err(ConfigError::invalid("uri", "must be a string"))
+        },
+        (None, Some(uri_regex)) => match uri_regex.as_str() {
+            // Anchored, so the pattern has to describe the whole path.
+            Some(uri_regex) => match Regex::new(&format!("^(?:{})$", uri_regex)) {
+                Ok(regex) => RoutePath::Regex(regex),
+                // The last line of regex errors says what is wrong, the others draw the pattern.
+                Err(err) => return Err(ConfigError::invalid("uri_regex", &format!("is not a valid regex: {}",
+                    err.to_string().lines().last().unwrap_or_default().trim_start_matches("error: "))))
+            },
+            None => return Err(ConfigError::invalid("uri_regex", "must be a string"))
         },
-        None => return Err("Every request must have uri".to_string())
+        (None, None) => return Err(ConfigError::invalid("uri", "or uri_regex is required"))
     };
     if let Some(name) = comp.get("name") {
         if let Some(name) = name.as_str() {
             let _component = match global.services.get(name) {
                 Some(component) => component,
-                None => return Err("Trying to use non declared service".to_string())
+                None => return Err(ConfigError::invalid("name", "is not a declared service"))
             };
             //return Ok((uri, *component));
         } else {
-            return Err("Name must be string".to_string());
+            return Err(ConfigError::invalid("name", "must be a string"));
         }
     }
-    return Ok((uri, global_parse(comp)));
+    let methods = parse_methods(&comp).map_err(|err| ConfigError::invalid("methods", &err))?;
+    let guards = parse_guards(&comp).map_err(|err| ConfigError::invalid("when", &err))?;
+    return Ok((path, Endpoint { methods, guards, component: global_parse(comp)? }));
+}
+
+/// Parses the hosts entries, returning report lines for everything wrong with them.
+pub fn parse_hosts(val:serde_json::Value, global:&GlobalState) -> Result<Vec<(String, Node)>, Vec<String>> {
+    let hosts = match val.as_array() {
+        Some(hosts) => hosts,
+        None => return Err(vec!["  /hosts: must be an array".to_string()])
+    };
+    let mut res: Vec<(String, Node)> = Vec::new();
+    let mut errors: Vec<String> = Vec::new();
+    for (index, host) in hosts.iter().enumerate() {
+        let patterns: Vec<String> = match host.get("host") {
+            Some(serde_json::Value::String(pattern)) => vec![pattern.to_string()],
+            Some(serde_json::Value::Array(patterns)) => {
+                let mut list: Vec<String> = Vec::new();
+                for pattern in patterns {
+                    match pattern.as_str() {
+                        Some(pattern) => list.push(pattern.to_string()),
+                        None => errors.push(format!("  /hosts/{}/host: every host pattern must be a string", index))
+                    }
+                }
+                list
+            },
+            Some(_) => {
+                errors.push(format!("  /hosts/{}/host: must be a string or an array of strings", index));
+                continue;
+            },
+            None => {
+                errors.push(format!("  /hosts/{}/host: is required", index));
+                continue;
+            }
+        };
+        let tree = match host.get("requests") {
+            Some(requests) => match parse_requests(requests.clone(), global) {
+                Ok(tree) => tree,
+                Err(request_errors) => {
+                    errors.extend(report_request_errors(&request_errors, &format!("/hosts/{}/requests", index)));
+                    continue;
+                }
+            },
+            None => Node::new()
+        };
+        for pattern in patterns {
+            res.push((pattern, tree.clone()));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(res)
 }
 
 pub fn parse_services(services:&mut HashMap<String, Service>, val:serde_json::Value) -> Result<(), String> {
     if let Some(service_array) = val.as_array() {
-        for serv in service_array {
+        for serv in service_array.iter().filter(|serv| is_enabled(serv)) {
             let (name, service) = parse_service(serv.clone())?;
             services.insert(name, service);
         }
     };
+    check_dependencies(services)
+}
+
+/// Makes sure every `depends_on` names a declared service and that no service
+/// ends up waiting on itself.
+pub fn check_dependencies(services:&HashMap<String, Service>) -> Result<(), String> {
+    fn visit<'a>(name: &'a str, services: &'a HashMap<String, Service>, path: &mut Vec<&'a str>, done: &mut Vec<&'a str>) -> Result<(), String> {
+        if done.contains(&name) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|visited| *visited == name) {
+            return Err(format!("Services depend on each other: {} -> {}", path[start..].join(" -> "), name));
+        }
+        path.push(name);
+        for dependency in services[name].depends_on() {
+            if !services.contains_key(dependency) {
+                return Err(format!("Service {} depends on unknown service {}", name, dependency));
+            }
+            visit(dependency, services, path, done)?;
+        }
+        path.pop();
+        done.push(name);
+        Ok(())
+    }
+    let mut done: Vec<&str> = Vec::new();
+    for name in services.keys() {
+        visit(name, services, &mut Vec::new(), &mut done)?;
+    }
     Ok(())
 }
 
+/// Services declared twice and routes that would shadow each other, usually
+/// from two included fragments. Each is reported with the file it comes from.
+pub fn check_duplicates(config:&serde_json::Value, origins:&Origins) -> Result<(), String> {
+    fn items(config: &serde_json::Value, key: &str) -> Vec<serde_json::Value> {
+        return config.get(key).and_then(|list| list.as_array()).cloned().unwrap_or_default();
+    }
+    fn origin(files: &[String], index: usize) -> &str {
+        return files.get(index).map(|file| file.as_str()).unwrap_or("?");
+    }
+    fn check_routes(requests: &[serde_json::Value], pointer: &str, files: &dyn Fn(usize) -> String, errors: &mut Vec<String>) {
+        let mut seen: Vec<(serde_json::Value, usize)> = Vec::new();
+        for (index, request) in requests.iter().enumerate().filter(|(_, request)| is_enabled(request)) {
+            let key = route_key(request);
+            match seen.iter().find(|(seen_key, _)| *seen_key == key) {
+                Some((_, first)) => {
+                    let route = request.get("uri").or(request.get("uri_regex")).and_then(|uri| uri.as_str()).unwrap_or_default();
+                    errors.push(format!("  {}/{} ({}): route {} is already declared at {}/{} ({})", pointer, index, files(index), route, pointer, first, files(*first)));
+                },
+                None => seen.push((key, index))
+            }
+        }
+    }
+    let mut errors: Vec<String> = Vec::new();
+    let mut names: HashMap<String, usize> = HashMap::new();
+    for (index, service) in items(config, "services").iter().enumerate().filter(|(_, service)| is_enabled(service)) {
+        if let Some(name) = service.get("name").and_then(|name| name.as_str()) {
+            match names.get(name) {
+                Some(first) => errors.push(format!("  /services/{} ({}): service {} is already declared at /services/{} ({})",
+                    index, origin(&origins.services, index), name, first, origin(&origins.services, *first))),
+                None => { names.insert(name.to_string(), index); }
+            }
+        }
+    }
+    check_routes(&items(config, "requests"), "/requests", &|index| origin(&origins.requests, index).to_string(), &mut errors);
+    for (index, host) in items(config, "hosts").iter().enumerate() {
+        check_routes(&items(host, "requests"), &format!("/hosts/{}/requests", index), &|_| origin(&origins.hosts, index).to_string(), &mut errors);
+    }
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(format!("Config has {} error(s):\n{}", errors.len(), errors.join("\n")))
+}
+
 pub fn parse_service(val:serde_json::Value) -> Result<(String, Service), String> {
     let service = match val.as_object() {
         Some(c) => c,
@@ -72,7 +296,96 @@ pub fn parse_service(val:serde_json::Value) -> Result<(String, Service), String>
     return Ok((name, Service::from_val(val)?));
 }
 
-pub fn parse_config(file_path: &str) -> Result<(Node, GlobalState), String> {
+/// Syntaxes a config file can be written in. They all map onto the same JSON model.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ConfigFormat {
+    Json,
+    Yaml,
+    Toml
+}
+
+impl ConfigFormat {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        return match name.to_ascii_lowercase().as_str() {
+            "json" => Ok(ConfigFormat::Json),
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "toml" => Ok(ConfigFormat::Toml),
+            _ => Err(format!("Unknown config format {}, expected json, yaml or toml", name))
+        };
+    }
+
+    /// Picks the format by file extension, JSON when there is none it knows.
+    pub fn from_path(path: &str) -> Self {
+        return match Path::new(path).extension().and_then(|extension| extension.to_str()) {
+            Some(extension) => ConfigFormat::from_name(extension).unwrap_or(ConfigFormat::Json),
+            None => ConfigFormat::Json
+        };
+    }
+}
+
+/// Line and column (both from 1) of a byte offset, for parsers that only report offsets.
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches("\n").count() + 1;
+    let column = before.len() - before.rfind("\n").map(|newline| newline + 1).unwrap_or(0) + 1;
+    return (line, column);
+}
+
+/// The `at line X column Y` parsers end their messages with.
+static LOCATION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\s+at line \d+ column \d+$").unwrap());
+
+/// Drops the location from a parser message, as it is reported on its own.
+fn strip_location(message: &str) -> String {
+    return LOCATION.replace(message, "").to_string();
+}
+
+/// Reads config text into the JSON model, with the location of syntax errors.
+pub fn read_config(text: &str, format: ConfigFormat) -> Result<serde_json::Value, String> {
+    return match format {
+        ConfigFormat::Json => serde_json::from_str(text).map_err(|err| {
+            format!("Invalid JSON at line {}, column {}: {}", err.line(), err.column(), strip_location(&err.to_string()))
+        }),
+        ConfigFormat::Yaml => serde_yaml_ng::from_str(text).map_err(|err| match err.location() {
+            Some(location) => format!("Invalid YAML at line {}, column {}: {}", location.line(), location.column(), strip_location(&err.to_string())),
+            None => format!("Invalid YAML: {}", err)
+        }),
+        ConfigFormat::Toml => toml::from_str(text).map_err(|err| match err.span() {
+            Some(span) => {
+                let (line, column) = line_column(text, span.start);
+                format!("Invalid TOML at line {}, column {}: {}", line, column, err.message())
+            },
+            None => format!("Invalid TOML: {}", err.message())
+        })
+    };
+}
+
+/// Writes the JSON model of a config back out in `format`.
+pub fn write_config(config: &serde_json::Value, format: ConfigFormat) -> Result<String, String> {
+    return match format {
+        ConfigFormat::Json => serde_json::to_string_pretty(config).map(|text| text + "\n").map_err(|err| err.to_string()),
+        ConfigFormat::Yaml => serde_yaml_ng::to_string(config).map_err(|err| err.to_string()),
+        ConfigFormat::Toml => toml::to_string_pretty(config).map_err(|err| format!("Config cannot be written as TOML: {}", err))
+    };
+}
+
+/// Rewrites the config at `input` in another format, to `output` or stdout.
+pub fn convert_config(input: &str, from: ConfigFormat, output: Option<&str>, to: ConfigFormat) -> Result<(), String> {
+    let text = match fs::read_to_string(input) {
+        Ok(text) => text,
+        Err(_) => return Err(format!("File {} does not exist", input))
+    };
+    let config = read_config(&text, from)?;
+    let converted = write_config(&config, to)?;
+    match output {
+        Some(output) => fs::write(output, converted).map_err(|err| format!("Could not write {}: {}", output, err)),
+        None => {
+            print!("{}", converted);
+            Ok(())
+        }
+    }
+}
+
+pub fn parse_config(file_path: &str, format: ConfigFormat, profiles: &[String]) -> Result<(Node, GlobalState), String> {
     let config = fs::read_to_string(file_path);
     let config = match config {
         Ok(config) => config,
@@ -80,23 +393,34 @@ pub fn parse_config(file_path: &str) -> Result<(Node, GlobalState), String> {
             return Err(format!("File {} does not exist", file_path));
         }
     };
-    parse_raw_config(config)
+    let config = read_config(&config, format)?;
+    let (config, origins) = apply_layers(config, file_path, format, profiles)?;
+    parse_raw_config(config, &origins)
 }
 
-pub fn parse_raw_config(config: String) -> Result<(Node, GlobalState), String> {
-    let config: Result<serde_json::Value, serde_json::Error> = serde_json::from_str(&config);
-    let config = match config {
-        Ok(config) => config,
-        Err(_) => {
-            return Err(format!("File does not seem to be json formated"));
-        }
-    };
-    
+pub fn parse_raw_config(mut config: serde_json::Value, origins: &Origins) -> Result<(Node, GlobalState), String> {
+    config_schema().coerce(&mut config);
+    validate_config(&config)?;
+    check_duplicates(&config, origins)?;
+
     let mut global: GlobalState = GlobalState::empty();
     parse_root(config.clone(), &mut global)?;
-    let tree = match config.get("requests") {
-        Some(req) => parse_requests(req.clone(), &mut global)?,
+    let mut errors: Vec<String> = Vec::new();
+    let mut tree = match config.get("requests") {
+        Some(req) => parse_requests(req.clone(), &global).unwrap_or_else(|request_errors| {
+            errors.extend(report_request_errors(&request_errors, "/requests"));
+            Node::new()
+        }),
         None => Node::new()
     };
+    if let Some(hosts) = config.get("hosts") {
+        match parse_hosts(hosts.clone(), &global) {
+            Ok(hosts) => tree.hosts = hosts,
+            Err(host_errors) => errors.extend(host_errors)
+        }
+    }
+    if !errors.is_empty() {
+        return Err(format!("Config has {} error(s):\n{}", errors.len(), errors.join("\n")));
+    }
     Ok((tree, global))
 }
@@ -0,0 +1,79 @@
+use std::{fs, sync::{Arc, Mutex}, thread, time::{Duration, SystemTime}};
+
+use crate::{layers::layer_files, parsing::{ConfigFormat, parse_config}, structs::data::{GlobalState, Node}, supervisor::{start_service, stop_service}};
+
+/// Modification times of the config and its layers; a layer that is missing counts as `None`.
+fn modified(file_name: &str, format: ConfigFormat) -> Vec<Option<SystemTime>> {
+    return layer_files(file_name, format).iter()
+        .map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .collect();
+}
+
+/// Watches the config file, the files it includes, its local override and `.env`, and swaps in the new
+/// tree and global state whenever one of them changes. Requests already running keep the snapshot they started with,
+/// so they and upgraded connections are not cut by a reload.
+pub fn watch_config(file_name: String, format: ConfigFormat, profiles: Vec<String>, tree: Arc<Mutex<Node>>, global: Arc<Mutex<GlobalState>>) {
+    let mut last_modified = modified(&file_name, format);
+    loop {
+        thread::sleep(Duration::from_millis(500));
+        let current = modified(&file_name, format);
+        // The main file can be missing for a moment while an editor replaces it.
+        if current[0].is_none() || current == last_modified {
+            continue;
+        }
+        // Editors often save in several writes, give them a moment to finish.
+        thread::sleep(Duration::from_millis(100));
+        last_modified = modified(&file_name, format);
+        reload(&file_name, format, &profiles, &tree, &global);
+    }
+}
+
+/// Parses the config again and swaps it in. A config that does not parse
+/// leaves the old one active.
+fn reload(file_name: &str, format: ConfigFormat, profiles: &[String], tree: &Arc<Mutex<Node>>, global: &Arc<Mutex<GlobalState>>) {
+    let (new_tree, mut new_global) = match parse_config(file_name, format, profiles) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Config {} not reloaded: {}", file_name, err);
+            return;
+        }
+    };
+    let mut started: Vec<String> = Vec::new();
+    {
+        // Same order as `get_ro_snapshot`.
+        let mut global = global.lock().unwrap_or_else(|err| err.into_inner());
+        let mut tree = tree.lock().unwrap_or_else(|err| err.into_inner());
+        // Processes of services that are gone, or no longer have a command, are stopped first.
+        for (name, old) in global.services.iter() {
+            if old.process().is_some() && new_global.services.get(name).is_none_or(|service| service.process().is_none()) {
+                stop_service(name);
+            }
+        }
+        for (name, service) in new_global.services.iter_mut() {
+            match global.services.get(name) {
+                Some(old) => {
+                    service.carry_over(old);
+                    if old.process().is_some() && old.process() != service.process() {
+                        println!("Service {} command changed, restart DevSync to apply it", name);
+                    } else if old.process().is_none() && service.process().is_some() {
+                        started.push(name.to_string());
+                    }
+                },
+                None => if service.process().is_some() {
+                    started.push(name.to_string());
+                }
+            }
+        }
+        // The listener is already bound, so it keeps its address and certificate.
+        if new_global.exposed != global.exposed {
+            println!("Changes to exposed take effect after a restart");
+            new_global.exposed = global.exposed.clone();
+        }
+        *global = new_global;
+        *tree = new_tree;
+        for name in &started {
+            start_service(name, &global);
+        }
+    }
+    println!("Reloaded config {}", file_name);
+}
@@ -22,6 +22,8 @@ pub fn setup(tree: Arc<Mutex<Node>>, global: Arc<Mutex<GlobalState>>, m: &ArgMat
             net.listen(tree, global);
         });
     };
-    loop {}
+    loop {
+        thread::park();
+    }
 }
 
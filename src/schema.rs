@@ -0,0 +1,322 @@
+use std::{collections::BTreeMap, sync::LazyLock};
+
+use serde_json::{Map, Value, json};
+
+/// The shape of a piece of config. Config types describe themselves with it
+/// next to their `from_json`, and the same description is used to validate a
+/// config before parsing and to generate `setup.schema.json`.
+pub enum Schema {
+    Any,
+    String,
+    Integer,
+    Number,
+    Boolean,
+    /// Exactly one of these values.
+    Enum(Vec<Value>),
+    Array(Box<Schema>),
+    /// An object with these fields and no others.
+    Object(Vec<Field>),
+    /// An object with any keys, every value matching.
+    Map(Box<Schema>),
+    /// An object whose fields depend on the value of its `tag` field.
+    Tagged(&'static str, Vec<(&'static str, Vec<Field>)>),
+    OneOf(Vec<Schema>),
+    /// A schema shared by several places, kept once under `definitions`.
+    Ref(&'static str, fn() -> Schema)
+}
+
+/// A string that is one `${VAR}` placeholder, allowed wherever a typed value is.
+static PLACEHOLDER: LazyLock<Value> = LazyLock::new(|| json!({"type": "string", "pattern": "^\\$\\{[A-Za-z_][A-Za-z0-9_]*(:-[^}]*)?\\}$"}));
+
+pub struct Field {
+    pub name: &'static str,
+    pub schema: Schema,
+    pub required: bool
+}
+
+impl Field {
+    pub fn new(name: &'static str, schema: Schema) -> Self {
+        return Field { name, schema, required: false };
+    }
+
+    pub fn required(name: &'static str, schema: Schema) -> Self {
+        return Field { name, schema, required: true };
+    }
+}
+
+/// A place in the config that does not match its schema.
+pub struct ValidationError {
+    /// JSON pointer to the offending value, e.g. `/requests/3/service`.
+    pub pointer: String,
+    pub message: String
+}
+
+fn escape_pointer(key: &str) -> String {
+    return key.replace("~", "~0").replace("/", "~1");
+}
+
+fn type_name(value: &Value) -> &'static str {
+    return match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object"
+    };
+}
+
+impl Schema {
+    pub fn strings(values: &[&str]) -> Self {
+        return Schema::Enum(values.iter().map(|value| json!(value)).collect());
+    }
+
+    pub fn array(items: Schema) -> Self {
+        return Schema::Array(Box::new(items));
+    }
+
+    pub fn map(values: Schema) -> Self {
+        return Schema::Map(Box::new(values));
+    }
+
+    /// Short description used in error messages.
+    fn describe(&self) -> String {
+        return match self {
+            Schema::Any => "anything".to_string(),
+            Schema::String => "string".to_string(),
+            Schema::Integer => "integer".to_string(),
+            Schema::Number => "number".to_string(),
+            Schema::Boolean => "boolean".to_string(),
+            Schema::Enum(values) => format!("one of {}", values.iter().map(|value| value.to_string()).collect::<Vec<String>>().join(", ")),
+            Schema::Array(items) => format!("array of {}", items.describe()),
+            Schema::Object(_) | Schema::Map(_) | Schema::Tagged(_, _) => "object".to_string(),
+            Schema::OneOf(options) => options.iter().map(|option| option.describe()).collect::<Vec<String>>().join(" or "),
+            Schema::Ref(_, schema) => schema().describe()
+        };
+    }
+
+    /// Checks `value` and collects every mismatch, not just the first.
+    pub fn validate(&self, value: &Value, pointer: &str, errors: &mut Vec<ValidationError>) {
+        let mismatch = |errors: &mut Vec<ValidationError>| errors.push(ValidationError {
+            pointer: pointer.to_string(),
+            message: format!("expected {}, found {}", self.describe(), type_name(value))
+        });
+        match self {
+            Schema::Any => {},
+            Schema::String => if !value.is_string() { mismatch(errors) },
+            Schema::Integer => if !(value.is_i64() || value.is_u64()) { mismatch(errors) },
+            Schema::Number => if !value.is_number() { mismatch(errors) },
+            Schema::Boolean => if !value.is_boolean() { mismatch(errors) },
+            Schema::Enum(values) => if !values.contains(value) {
+                errors.push(ValidationError { pointer: pointer.to_string(), message: format!("expected {}, found {}", self.describe(), value) });
+            },
+            Schema::Array(items) => match value.as_array() {
+                Some(list) => list.iter().enumerate().for_each(|(index, item)| items.validate(item, &format!("{}/{}", pointer, index), errors)),
+                None => mismatch(errors)
+            },
+            Schema::Object(fields) => match value.as_object() {
+                Some(object) => validate_fields(fields, None, object, pointer, errors),
+                None => mismatch(errors)
+            },
+            Schema::Map(values) => match value.as_object() {
+                Some(object) => object.iter().for_each(|(key, item)| values.validate(item, &format!("{}/{}", pointer, escape_pointer(key)), errors)),
+                None => mismatch(errors)
+            },
+            Schema::Tagged(tag, variants) => match value.as_object() {
+                Some(object) => match object.get(*tag).and_then(|tag| tag.as_str()) {
+                    Some(kind) => match variants.iter().find(|(name, _)| *name == kind) {
+                        Some((_, fields)) => validate_fields(fields, Some(tag), object, pointer, errors),
+                        None => errors.push(ValidationError {
+                            pointer: format!("{}/{}", pointer, tag),
+                            message: format!("unknown {} {}, expected one of {}", tag, kind, variants.iter().map(|(name, _)| *name).collect::<Vec<&str>>().join(", "))
+                        })
+                    },
+                    None => errors.push(ValidationError { pointer: format!("{}/{}", pointer, tag), message: "is required and must be a string".to_string() })
+                },
+                None => mismatch(errors)
+            },
+            Schema::OneOf(options) => {
+                let matches = options.iter().any(|option| {
+                    let mut option_errors: Vec<ValidationError> = Vec::new();
+                    option.validate(value, pointer, &mut option_errors);
+                    option_errors.is_empty()
+                });
+                if !matches {
+                    mismatch(errors);
+                }
+            },
+            Schema::Ref(_, schema) => schema().validate(value, pointer, errors)
+        }
+    }
+
+    /// Turns strings into numbers and booleans where this shape expects them,
+    /// so `"port": "${API_PORT:-3001}"` is a number once interpolated. Strings
+    /// anywhere else are left alone, and so is anything that does not parse.
+    pub fn coerce(&self, value: &mut Value) {
+        match (self, value) {
+            (Schema::Integer, value @ Value::String(_)) => {
+                if let Some(number) = value.as_str().and_then(|text| serde_json::from_str::<Value>(text.trim()).ok()).filter(|number| number.is_i64() || number.is_u64()) {
+                    *value = number;
+                }
+            },
+            (Schema::Number, value @ Value::String(_)) => {
+                if let Some(number) = value.as_str().and_then(|text| serde_json::from_str::<Value>(text.trim()).ok()).filter(|number| number.is_number()) {
+                    *value = number;
+                }
+            },
+            (Schema::Boolean, value @ Value::String(_)) => match value.as_str().map(|text| text.trim()) {
+                Some("true") => *value = Value::Bool(true),
+                Some("false") => *value = Value::Bool(false),
+                _ => {}
+            },
+            (Schema::Array(items), Value::Array(list)) => list.iter_mut().for_each(|item| items.coerce(item)),
+            (Schema::Object(fields), Value::Object(object)) => coerce_fields(fields, object),
+            (Schema::Map(values), Value::Object(object)) => object.values_mut().for_each(|item| values.coerce(item)),
+            (Schema::Tagged(tag, variants), Value::Object(object)) => {
+                let kind = object.get(*tag).and_then(|kind| kind.as_str()).map(|kind| kind.to_string());
+                if let Some((_, fields)) = variants.iter().find(|(name, _)| Some(*name) == kind.as_deref()) {
+                    coerce_fields(fields, object);
+                }
+            },
+            // The first option the value fits once coerced, if any.
+            (Schema::OneOf(options), value) => {
+                for option in options {
+                    let mut coerced = value.clone();
+                    option.coerce(&mut coerced);
+                    let mut errors: Vec<ValidationError> = Vec::new();
+                    option.validate(&coerced, "", &mut errors);
+                    if errors.is_empty() {
+                        *value = coerced;
+                        return;
+                    }
+                }
+            },
+            (Schema::Ref(_, schema), value) => schema().coerce(value),
+            _ => {}
+        }
+    }
+
+    /// The JSON schema for this shape. Shared schemas are collected into `definitions`.
+    pub fn to_json_schema(&self, definitions: &mut BTreeMap<String, Value>) -> Value {
+        return match self {
+            Schema::Any => json!({}),
+            Schema::String => json!({"type": "string"}),
+            // Numbers and booleans may also come from `${VAR:-default}`.
+            Schema::Integer => json!({"anyOf": [{"type": "integer"}, PLACEHOLDER.clone()]}),
+            Schema::Number => json!({"anyOf": [{"type": "number"}, PLACEHOLDER.clone()]}),
+            Schema::Boolean => json!({"anyOf": [{"type": "boolean"}, PLACEHOLDER.clone()]}),
+            Schema::Enum(values) => json!({"enum": values}),
+            Schema::Array(items) => json!({"type": "array", "items": items.to_json_schema(definitions)}),
+            Schema::Object(fields) => fields_to_json_schema(fields, None, definitions),
+            Schema::Map(values) => json!({"type": "object", "additionalProperties": values.to_json_schema(definitions)}),
+            Schema::Tagged(tag, variants) => json!({
+                "oneOf": variants.iter()
+                    .map(|(name, fields)| fields_to_json_schema(fields, Some((tag, name)), definitions))
+                    .collect::<Vec<Value>>()
+            }),
+            Schema::OneOf(options) => json!({
+                "oneOf": options.iter().map(|option| option.to_json_schema(definitions)).collect::<Vec<Value>>()
+            }),
+            Schema::Ref(name, schema) => {
+                if !definitions.contains_key(*name) {
+                    // Placeholder first, so a schema that refers to itself ends.
+                    definitions.insert(name.to_string(), Value::Null);
+                    let definition = schema().to_json_schema(definitions);
+                    definitions.insert(name.to_string(), definition);
+                }
+                json!({"$ref": format!("#/definitions/{}", name)})
+            }
+        };
+    }
+
+    /// A complete schema document with this shape at its root.
+    pub fn to_json_schema_document(&self) -> Value {
+        let mut definitions: BTreeMap<String, Value> = BTreeMap::new();
+        let mut document = self.to_json_schema(&mut definitions);
+        document["$schema"] = json!("http://json-schema.org/draft-07/schema#");
+        if !definitions.is_empty() {
+            document["definitions"] = json!(definitions);
+        }
+        return document;
+    }
+}
+
+fn validate_fields(fields: &[Field], tag: Option<&str>, object: &Map<String, Value>, pointer: &str, errors: &mut Vec<ValidationError>) {
+    for field in fields {
+        match object.get(field.name) {
+            Some(value) => field.schema.validate(value, &format!("{}/{}", pointer, escape_pointer(field.name)), errors),
+            None if field.required => errors.push(ValidationError {
+                pointer: format!("{}/{}", pointer, escape_pointer(field.name)),
+                message: "is required".to_string()
+            }),
+            None => {}
+        }
+    }
+    for key in object.keys() {
+        if Some(key.as_str()) != tag && !fields.iter().any(|field| field.name == key) {
+            errors.push(ValidationError { pointer: format!("{}/{}", pointer, escape_pointer(key)), message: "unknown field".to_string() });
+        }
+    }
+}
+
+fn coerce_fields(fields: &[Field], object: &mut Map<String, Value>) {
+    for field in fields {
+        if let Some(value) = object.get_mut(field.name) {
+            field.schema.coerce(value);
+        }
+    }
+}
+
+fn fields_to_json_schema(fields: &[Field], tag: Option<(&str, &str)>, definitions: &mut BTreeMap<String, Value>) -> Value {
+    let mut properties = Map::new();
+    let mut required: Vec<&str> = Vec::new();
+    if let Some((tag, name)) = tag {
+        properties.insert(tag.to_string(), json!({"const": name}));
+        required.push(tag);
+    }
+    for field in fields {
+        properties.insert(field.name.to_string(), field.schema.to_json_schema(definitions));
+        if field.required {
+            required.push(field.name);
+        }
+    }
+    let mut schema = json!({"type": "object", "properties": properties, "additionalProperties": false});
+    if !required.is_empty() {
+        schema["required"] = json!(required);
+    }
+    return schema;
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::parsing::config_schema;
+
+    /// `setup.schema.json` is generated by the `schema` subcommand; regenerate it when this fails.
+    #[test]
+    fn committed_schema_matches_config_types() {
+        let generated = serde_json::to_string_pretty(&config_schema().to_json_schema_document()).unwrap();
+        let committed = include_str!("../setup.schema.json").replace("\r\n", "\n");
+        assert_eq!(committed.trim_end(), generated, "setup.schema.json is out of date, run `dev_services schema > setup.schema.json`");
+    }
+
+    #[test]
+    fn coerce_only_touches_typed_fields() {
+        let schema = Schema::Object(vec![
+            Field::new("port", Schema::Integer),
+            Field::new("ratio", Schema::Number),
+            Field::new("secure", Schema::Boolean),
+            Field::new("headers", Schema::map(Schema::String)),
+            Field::new("timeout", Schema::OneOf(vec![Schema::Integer, Schema::strings(&["never"])]))
+        ]);
+        let mut value = json!({"port": "3001", "ratio": "0.5", "secure": "true", "headers": {"X-Api-Version": "2"}, "timeout": "30"});
+        schema.coerce(&mut value);
+        assert_eq!(value, json!({"port": 3001, "ratio": 0.5, "secure": true, "headers": {"X-Api-Version": "2"}, "timeout": 30}));
+
+        let mut value = json!({"port": "http", "timeout": "never"});
+        schema.coerce(&mut value);
+        assert_eq!(value, json!({"port": "http", "timeout": "never"}));
+    }
+}
@@ -1,3 +1,5 @@
 pub mod http;
 pub mod data;
 pub mod components;
+pub mod connection;
+pub mod recording;
@@ -1,41 +1,163 @@
-use std::fs;
+use std::{collections::HashMap, fs, io, sync::LazyLock, thread, time::Duration};
 
-use crate::structs::{data::{Component, GlobalState}, http::{Request, Response, merge_paths, path_exists}};
+use regex::{Captures, Regex};
 
-pub fn global_parse(val:serde_json::Value) -> Box<dyn Component> {
-    let component = val.as_object().unwrap();
-    let component_type = component.get("type").unwrap().as_str().unwrap();
+use crate::schema::{Field, Schema};
 
-    match component_type {
-        "proxy" => Box::new(ProxyComponent::parse(val)),
-        "static" => Box::new(StaticComponent::parse(val)),
-        _ => panic!("Unknown component type")
-    }
+use crate::structs::{connection::Connection, data::{Component, ConfigError, GlobalState, Guard, host_name}, http::{Request, Response, merge_paths, path_exists, split_query, fill_params, get_header, percent_decode, status_text}, recording::{Recording, DEFAULT_RECORDING_FILE}};
+
+pub fn global_parse(val:serde_json::Value) -> Result<Box<dyn Component>, ConfigError> {
+    let component_type = match val.get("type") {
+        Some(component_type) => match component_type.as_str() {
+            Some(component_type) => component_type.to_string(),
+            None => return Err(ConfigError::invalid("type", "must be a string"))
+        },
+        None => return Err(ConfigError::missing("type"))
+    };
+
+    Ok(match component_type.as_str() {
+        "proxy" => Box::new(ProxyComponent::parse(val)?),
+        "static" => Box::new(StaticComponent::parse(val)?),
+        "redirect" => Box::new(RedirectComponent::parse(val)?),
+        "mock" => Box::new(MockComponent::parse(val)?),
+        "replay" => Box::new(ReplayComponent::parse(val)?),
+        "health" => Box::new(HealthComponent::parse(val)?),
+        _ => return Err(ConfigError::UnknownType { index: 0, kind: component_type })
+    })
+}
+
+/// Fields every request has whatever its component.
+fn route_fields(fields: Vec<Field>) -> Vec<Field> {
+    let mut route = vec![
+        Field::new("uri", Schema::String),
+        Field::new("uri_regex", Schema::String),
+        Field::new("methods", Schema::array(Schema::String)),
+        Field::new("when", Schema::Ref("conditions", || Schema::array(Guard::schema()))),
+        Field::new("name", Schema::String),
+        Field::new("enabled", Schema::Boolean)
+    ];
+    route.extend(fields);
+    return route;
+}
+
+/// Schema of one request, with the same component types `global_parse` knows.
+pub fn request_schema() -> Schema {
+    return Schema::Tagged("type", vec![
+        ("proxy", route_fields(ProxyComponent::fields())),
+        ("static", route_fields(StaticComponent::fields())),
+        ("redirect", route_fields(RedirectComponent::fields())),
+        ("mock", route_fields(MockComponent::fields())),
+        ("replay", route_fields(ReplayComponent::fields())),
+        ("health", route_fields(HealthComponent::fields()))
+    ]);
 }
 
 #[derive(Clone)]
 pub struct ProxyComponent {
-    pub service: String
+    pub service: String,
+    /// Upstream path to send instead of the remaining uri, with `{name}` filled from route params.
+    pub path: Option<String>,
+    /// Seconds an upgraded (websocket) connection may go without traffic either way.
+    pub upgrade_idle_timeout: u64,
+    /// File every exchange is appended to, for a `replay` component to serve later.
+    pub record: Option<String>,
+    /// Keep cookies and credentials in recordings instead of leaving them out.
+    pub record_secrets: bool
+}
+
+impl ProxyComponent {
+    fn rewrite(&self, req: &mut Request) {
+        if let Some(path) = &self.path {
+            let (_, query) = split_query(&req.uri);
+            let path = fill_params(path, &req.params).trim_start_matches("/").to_string();
+            req.uri = match query.is_empty() {
+                true => path,
+                false => format!("{}?{}", path, query)
+            };
+        }
+    }
 }
 
 impl Component for ProxyComponent {
-    fn parse(val:serde_json::Value) -> Self where Self: Sized {
-        let service = match val.as_object() {
-            Some(component) => match component.get("service") {
-                Some(service) => match service.as_str() {
-                    Some(service_name) => service_name,
-                    None => panic!("Proxy component service name must be type of string")
-                },
-                None => panic!("Proxy component must have service")
+    fn fields() -> Vec<Field> where Self: Sized {
+        return vec![
+            Field::required("service", Schema::String),
+            Field::new("path", Schema::String),
+            Field::new("upgrade_idle_timeout", Schema::Integer),
+            Field::new("record", Schema::OneOf(vec![Schema::String, Schema::Boolean])),
+            Field::new("record_secrets", Schema::Boolean)
+        ];
+    }
+    fn parse(val:serde_json::Value) -> Result<Self, ConfigError> where Self: Sized {
+        let service = match val.get("service") {
+            Some(service) => match service.as_str() {
+                Some(service_name) => service_name,
+                None => return Err(ConfigError::invalid("service", "must be a service name"))
+            },
+            None => return Err(ConfigError::missing("service"))
+        };
+        let upgrade_idle_timeout = match val.get("upgrade_idle_timeout") {
+            Some(timeout) => match timeout.as_u64() {
+                Some(timeout) => timeout,
+                None => return Err(ConfigError::invalid("upgrade_idle_timeout", "must be a positive number"))
+            },
+            None => 300
+        };
+        let path = match val.get("path") {
+            Some(path) => match path.as_str() {
+                Some(path) => Some(path.to_string()),
+                None => return Err(ConfigError::invalid("path", "must be a string"))
+            },
+            None => None
+        };
+        let record = match val.get("record") {
+            Some(serde_json::Value::Bool(true)) => Some(DEFAULT_RECORDING_FILE.to_string()),
+            Some(serde_json::Value::Bool(false)) => None,
+            Some(record) => match record.as_str() {
+                Some(record) => Some(record.to_string()),
+                None => return Err(ConfigError::invalid("record", "must be a file name or boolean"))
+            },
+            None => None
+        };
+        let record_secrets = match val.get("record_secrets") {
+            Some(record_secrets) => match record_secrets.as_bool() {
+                Some(record_secrets) => record_secrets,
+                None => return Err(ConfigError::invalid("record_secrets", "must be a boolean"))
             },
-            None => panic!("Error while parsing proxy component")
+            None => false
         };
-        return ProxyComponent { service: service.to_string() };
+        return Ok(ProxyComponent { service: service.to_string(), path, upgrade_idle_timeout, record, record_secrets });
     }
     fn call(&self, global: &GlobalState, req: Request) -> Response {
-        return match global.services.get(&self.service) {
+        // Recordings keep the uri as this route saw it, before any rewrite.
+        let recorded = self.record.as_ref().map(|_| req.clone());
+        let mut req = req;
+        self.rewrite(&mut req);
+        let res = match global.services.get(&self.service) {
             Some(service) => service.fetch(req),
-            None => Response::not_found()
+            None => return Response::not_found()
+        };
+        if let (Some(record), Some(recorded)) = (&self.record, recorded) {
+            if let Err(err) = Recording::new(&recorded, &res, self.record_secrets).append(record) {
+                println!("Could not record to {}: {}", record, err);
+            }
+        }
+        return res;
+    }
+    fn serve(&self, global: &GlobalState, mut req: Request, client: &mut Connection) -> io::Result<()> {
+        // Recording needs whole bodies, so it goes through the buffered path.
+        if self.record.is_some() && req.method != "HEAD" && req.upgrade().is_none() {
+            client.read_body(&mut req)?;
+            let res = self.call(global, req);
+            return client.respond(res);
+        }
+        self.rewrite(&mut req);
+        return match global.services.get(&self.service) {
+            Some(service) => service.stream(req, client, Duration::from_secs(self.upgrade_idle_timeout)),
+            None => {
+                client.read_body(&mut req)?;
+                client.respond(Response::not_found())
+            }
         }
     }
 }
@@ -49,8 +171,11 @@ pub struct StaticComponent {
 impl StaticComponent {
     pub fn respond(path: &str) -> Response {
         let mut res = Response::new();
-        let mime_type = mime_guess::from_path(&path).first_or_octet_stream();
-        let file = fs::read_to_string(&path).unwrap();
+        let mime_type = mime_guess::from_path(path).first_or_octet_stream();
+        let file = match fs::read(path) {
+            Ok(file) => file,
+            Err(_) => return Response::not_found()
+        };
         res.headers.insert("Content-Type".to_string(), mime_type.to_string());
         res.body = file;
         return res;
@@ -58,27 +183,338 @@ impl StaticComponent {
 }
 
 impl Component for StaticComponent {
-    fn parse(val:serde_json::Value) -> Self where Self: Sized {
+    fn fields() -> Vec<Field> where Self: Sized {
+        return vec![
+            Field::new("path", Schema::String),
+            Field::new("index", Schema::String)
+        ];
+    }
+    fn parse(val:serde_json::Value) -> Result<Self, ConfigError> where Self: Sized {
         let mut path = "".to_string();
         let mut index = "index.html".to_string();
         if let Some(parse_path) = val.get("path") {
-            path = parse_path.as_str().unwrap().to_string();
+            path = parse_path.as_str().ok_or(ConfigError::invalid("path", "must be a string"))?.to_string();
         }
         if let Some(parse_index) = val.get("index") {
-            index = parse_index.as_str().unwrap().to_string();
+            index = parse_index.as_str().ok_or(ConfigError::invalid("index", "must be a string"))?.to_string();
         }
-        return StaticComponent { path, index };
+        return Ok(StaticComponent { path, index });
     }
     fn call(&self, _global: &GlobalState, req: Request) -> Response {
-        let path = merge_paths(&self.path, &req.uri);
+        // Query strings, like the ones bundlers add to bust caches, do not name files.
+        let (uri, _) = split_query(&req.uri);
+        let path = merge_paths(&self.path, uri);
         if path_exists(&path) {
             return StaticComponent::respond(&path);
         } else if path_exists(&merge_paths(&path, &self.index)) {
             return StaticComponent::respond(&merge_paths(&path, &self.index));
-        } {
+        } else {
             return Response::not_found();
         }
+    }
+}
+
+
+/// Answers with a redirect to `to`, a template that may use `{path}` (the
+/// remaining uri), `{query}` (`?` and the query string, or nothing), `{host}`,
+/// `{hostname}` (host without port) and the route params.
+#[derive(Clone)]
+pub struct RedirectComponent {
+    pub to: String,
+    pub status_code: i64
+}
+
+impl Component for RedirectComponent {
+    fn fields() -> Vec<Field> where Self: Sized {
+        return vec![
+            Field::required("to", Schema::String),
+            Field::new("status", Schema::Enum(vec![301.into(), 302.into(), 307.into(), 308.into()]))
+        ];
+    }
+    fn parse(val:serde_json::Value) -> Result<Self, ConfigError> where Self: Sized {
+        let to = match val.get("to") {
+            Some(to) => match to.as_str() {
+                Some(to) => to.to_string(),
+                None => return Err(ConfigError::invalid("to", "must be a string"))
+            },
+            None => return Err(ConfigError::missing("to"))
+        };
+        let status_code = match val.get("status") {
+            Some(status) => match status.as_i64() {
+                Some(status) if [301, 302, 307, 308].contains(&status) => status,
+                _ => return Err(ConfigError::invalid("status", "must be one of 301, 302, 307, 308"))
+            },
+            None => 302
+        };
+        return Ok(RedirectComponent { to, status_code });
+    }
+    fn call(&self, _global: &GlobalState, req: Request) -> Response {
+        let (path, query) = split_query(&req.uri);
+        let host = get_header(&req.headers, "Host").cloned().unwrap_or_default();
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("path".to_string(), path.to_string());
+        values.insert("query".to_string(), match query.is_empty() {
+            true => String::new(),
+            false => format!("?{}", query)
+        });
+        values.insert("hostname".to_string(), host_name(&host).to_string());
+        values.insert("host".to_string(), host);
+        values.extend(req.params.clone());
+
+        let mut res = Response::new();
+        res.status_code = self.status_code;
+        res.status = status_text(self.status_code).to_string();
+        res.headers.insert("Location".to_string(), fill_params(&self.to, &values));
+        return res;
+    }
+}
+
+/// Looks up a dotted path such as `user.tags.0` in a JSON value.
+pub fn json_at<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    return path.split(".").try_fold(value, |value, key| match value {
+        serde_json::Value::Array(items) => key.parse::<usize>().ok().and_then(|index| items.get(index)),
+        _ => value.get(key)
+    });
+}
+
+/// A `{source.name}` placeholder of a mock template.
+static TEMPLATE_PLACEHOLDER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"\{(params|query|headers|cookies|body)(?:\.([^{}\s"]+))?\}"#).unwrap());
+
+/// Fills `{params.name}`, `{query.name}`, `{headers.name}`, `{cookies.name}` and
+/// `{body.a.b}` (a field of the JSON body, `{body}` for all of it) from the request.
+/// Other braces are left alone, so JSON templates need no escaping.
+pub fn render_request_template(template: &str, req: &Request) -> String {
+    let json_body: Option<serde_json::Value> = serde_json::from_slice(&req.body).ok();
+    return TEMPLATE_PLACEHOLDER.replace_all(template, |caps: &Captures| {
+        let name = caps.get(2).map(|name| name.as_str());
+        let value = match (&caps[1], name) {
+            ("params", Some(name)) => req.params.get(name).cloned(),
+            ("query", Some(name)) => req.query_param(name),
+            ("headers", Some(name)) => get_header(&req.headers, name).cloned(),
+            ("cookies", Some(name)) => req.cookie(name),
+            ("body", None) => Some(String::from_utf8_lossy(&req.body).to_string()),
+            ("body", Some(path)) => json_body.as_ref()
+                .and_then(|body| json_at(body, path))
+                .map(|value| match value {
+                    serde_json::Value::String(value) => value.to_string(),
+                    value => value.to_string()
+                }),
+            _ => None
+        };
+        value.unwrap_or_default()
+    }).to_string();
+}
+
+/// Answers with a fixed response, for endpoints whose service is not running.
+/// The body comes inline or from `body_file`, read on every request so it can be
+/// edited live, and is rendered with `render_request_template` when it is text.
+#[derive(Clone)]
+pub struct MockComponent {
+    pub status_code: i64,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+    pub body_file: Option<String>,
+    /// Milliseconds to wait before answering.
+    pub latency: u64
+}
+
+impl Component for MockComponent {
+    fn fields() -> Vec<Field> where Self: Sized {
+        return vec![
+            Field::new("status", Schema::Integer),
+            Field::new("headers", Schema::map(Schema::String)),
+            Field::new("body", Schema::Any),
+            Field::new("body_file", Schema::String),
+            Field::new("latency", Schema::Integer)
+        ];
+    }
+    fn parse(val:serde_json::Value) -> Result<Self, ConfigError> where Self: Sized {
+        let status_code = match val.get("status") {
+            Some(status) => match status.as_i64() {
+                Some(status) if (100..600).contains(&status) => status,
+                _ => return Err(ConfigError::invalid("status", "must be a valid status code"))
+            },
+            None => 200
+        };
+        let mut headers: HashMap<String, String> = HashMap::new();
+        if let Some(parse_headers) = val.get("headers") {
+            match parse_headers.as_object() {
+                Some(parse_headers) => for (key, value) in parse_headers {
+                    match value.as_str() {
+                        Some(value) => headers.insert(key.to_string(), value.to_string()),
+                        None => return Err(ConfigError::invalid("headers", "values must be strings"))
+                    };
+                },
+                None => return Err(ConfigError::invalid("headers", "must be an object"))
+            }
+        }
+        let body = match val.get("body") {
+            Some(serde_json::Value::String(body)) => body.to_string(),
+            // Inline JSON is served as JSON.
+            Some(body) => {
+                if get_header(&headers, "Content-Type").is_none() {
+                    headers.insert("Content-Type".to_string(), "application/json".to_string());
+                }
+                body.to_string()
+            },
+            None => String::new()
+        };
+        let body_file = match val.get("body_file") {
+            Some(body_file) => match body_file.as_str() {
+                Some(body_file) => Some(body_file.to_string()),
+                None => return Err(ConfigError::invalid("body_file", "must be a string"))
+            },
+            None => None
+        };
+        let latency = match val.get("latency") {
+            Some(latency) => match latency.as_u64() {
+                Some(latency) => latency,
+                None => return Err(ConfigError::invalid("latency", "must be a positive number"))
+            },
+            None => 0
+        };
+        return Ok(MockComponent { status_code, headers, body, body_file, latency });
+    }
+    fn call(&self, _global: &GlobalState, req: Request) -> Response {
+        if self.latency > 0 {
+            thread::sleep(Duration::from_millis(self.latency));
+        }
+        let mut res = Response::new();
+        res.status_code = self.status_code;
+        res.status = status_text(self.status_code).to_string();
+        res.headers = self.headers.clone();
+        let body = match &self.body_file {
+            Some(body_file) => match fs::read(body_file) {
+                Ok(body) => {
+                    if get_header(&res.headers, "Content-Type").is_none() {
+                        let mime_type = mime_guess::from_path(body_file).first_or_octet_stream();
+                        res.headers.insert("Content-Type".to_string(), mime_type.to_string());
+                    }
+                    body
+                },
+                Err(_) => return Response::server_error()
+            },
+            None => self.body.as_bytes().to_vec()
+        };
+        res.body = match String::from_utf8(body) {
+            Ok(text) => render_request_template(&text, &req).into_bytes(),
+            Err(err) => err.into_bytes()
+        };
+        return res;
+    }
+}
+
+/// Which part of the request body has to agree for a recording to be replayed.
+#[derive(Clone)]
+pub enum BodyMatch {
+    Ignore,
+    Exact,
+    /// Only these dotted fields of a JSON body, so ids and timestamps can differ.
+    Fields(Vec<String>)
+}
+
+impl BodyMatch {
+    fn fingerprint(&self, body: &[u8]) -> Vec<u8> {
+        return match self {
+            BodyMatch::Ignore => Vec::new(),
+            BodyMatch::Exact => body.to_vec(),
+            BodyMatch::Fields(fields) => {
+                let body: Option<serde_json::Value> = serde_json::from_slice(body).ok();
+                let values: Vec<Option<&serde_json::Value>> = fields.iter()
+                    .map(|field| body.as_ref().and_then(|body| json_at(body, field)))
+                    .collect();
+                serde_json::to_vec(&values).unwrap_or_default()
+            }
+        };
+    }
+}
+
+/// Query pairs in a stable order, so `?a=1&b=2` replays `?b=2&a=1`.
+fn normalized_query(query: &str) -> Vec<(String, String)> {
+    let mut pairs: Vec<(String, String)> = query
+        .split("&")
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once("=").unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect();
+    pairs.sort();
+    return pairs;
+}
 
+/// Serves responses recorded by a proxy with `record`, without the upstream
+/// running. A request is matched on method, path, query and `match_body`; when
+/// the same request was recorded more than once the latest recording wins.
+#[derive(Clone)]
+pub struct ReplayComponent {
+    pub file: String,
+    pub match_body: BodyMatch
+}
+
+impl Component for ReplayComponent {
+    fn fields() -> Vec<Field> where Self: Sized {
+        return vec![
+            Field::new("file", Schema::String),
+            Field::new("match_body", Schema::OneOf(vec![Schema::strings(&["ignore", "exact"]), Schema::array(Schema::String)]))
+        ];
+    }
+    fn parse(val:serde_json::Value) -> Result<Self, ConfigError> where Self: Sized {
+        let file = match val.get("file") {
+            Some(file) => match file.as_str() {
+                Some(file) => file.to_string(),
+                None => return Err(ConfigError::invalid("file", "must be a string"))
+            },
+            None => DEFAULT_RECORDING_FILE.to_string()
+        };
+        let match_body = match val.get("match_body") {
+            Some(serde_json::Value::String(mode)) => match mode.as_str() {
+                "ignore" => BodyMatch::Ignore,
+                "exact" => BodyMatch::Exact,
+                _ => return Err(ConfigError::invalid("match_body", "must be ignore, exact or a list of fields"))
+            },
+            Some(serde_json::Value::Array(fields)) => BodyMatch::Fields(fields.iter().map(|field| match field.as_str() {
+                Some(field) => Ok(field.to_string()),
+                None => Err(ConfigError::invalid("match_body", "fields must be strings"))
+            }).collect::<Result<Vec<String>, ConfigError>>()?),
+            Some(_) => return Err(ConfigError::invalid("match_body", "must be ignore, exact or a list of fields")),
+            None => BodyMatch::Exact
+        };
+        return Ok(ReplayComponent { file, match_body });
+    }
+    fn call(&self, _global: &GlobalState, req: Request) -> Response {
+        let recordings = match Recording::load(&self.file) {
+            Ok(recordings) => recordings,
+            Err(_) => return Response::not_found()
+        };
+        let (path, query) = split_query(&req.uri);
+        let query = normalized_query(query);
+        let fingerprint = self.match_body.fingerprint(&req.body);
+        return match recordings.iter().rev().find(|recording| recording.method == req.method
+            && recording.path == path
+            && normalized_query(&recording.query) == query
+            && self.match_body.fingerprint(&recording.request_body) == fingerprint) {
+            Some(recording) => recording.to_response(),
+            None => Response::not_found()
+        };
     }
 }
 
+/// Reports the state of every service instance as JSON, for scripts and dashboards.
+#[derive(Clone)]
+pub struct HealthComponent {}
+
+impl Component for HealthComponent {
+    fn fields() -> Vec<Field> where Self: Sized {
+        return vec![];
+    }
+    fn parse(_val:serde_json::Value) -> Result<Self, ConfigError> where Self: Sized {
+        return Ok(HealthComponent {});
+    }
+    fn call(&self, global: &GlobalState, _req: Request) -> Response {
+        let mut res = Response::new();
+        res.headers.insert("Content-Type".to_string(), "application/json".to_string());
+        res.body = serde_json::to_vec_pretty(&global.health_report()).unwrap_or_default();
+        return res;
+    }
+}
@@ -0,0 +1,147 @@
+use std::{io::{self, BufReader, prelude::*}, net::{Shutdown, TcpStream}, sync::{Arc, atomic::{AtomicU64, Ordering}}, thread, time::{Duration, Instant}};
+
+use crate::structs::http::{Request, Response, remove_header, HOP_BY_HOP_HEADERS};
+
+/// A socket that is either plain TCP or TLS. Clones share the same connection,
+/// so reading and writing can happen on different threads.
+pub trait Stream: Read + Write + Send {
+    fn try_clone_stream(&self) -> io::Result<Box<dyn Stream>>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn try_clone_stream(&self) -> io::Result<Box<dyn Stream>> {
+        return Ok(Box::new(self.try_clone()?));
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        return TcpStream::shutdown(self, how);
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        return TcpStream::set_read_timeout(self, timeout);
+    }
+}
+
+/// The client side of one exchange. Components get it in `Component::serve`
+/// to read the request body and write the response however suits them.
+pub struct Connection {
+    pub reader: BufReader<Box<dyn Stream>>,
+    /// Whether the connection stays open after the current response.
+    pub keep_alive: bool,
+    /// Whether the current request is `HEAD`, whose response carries no body.
+    pub head_only: bool,
+    pub idle_timeout: Duration
+}
+
+impl Connection {
+    pub fn new(stream: Box<dyn Stream>, idle_timeout: Duration) -> Self {
+        return Connection { reader: BufReader::new(stream), keep_alive: true, head_only: false, idle_timeout };
+    }
+
+    pub fn writer(&mut self) -> &mut Box<dyn Stream> {
+        return self.reader.get_mut();
+    }
+
+    /// Reads the whole body of `req`. A malformed one is answered with a 400
+    /// and the connection closed, as the next request cannot be found after it.
+    pub fn read_body(&mut self, req: &mut Request) -> io::Result<()> {
+        if req.read_body(&mut self.reader).is_some() {
+            return Ok(());
+        }
+        self.keep_alive = false;
+        self.respond(Response::bad_request())?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed request body"));
+    }
+
+    /// Replaces the hop-by-hop headers of a response with ones describing this connection.
+    pub fn set_connection_headers(&self, res: &mut Response) {
+        HOP_BY_HOP_HEADERS.iter().for_each(|name| remove_header(&mut res.headers, name));
+        if self.keep_alive {
+            res.headers.insert("Connection".to_string(), "keep-alive".to_string());
+            res.headers.insert("Keep-Alive".to_string(), format!("timeout={}", self.idle_timeout.as_secs()));
+        } else {
+            res.headers.insert("Connection".to_string(), "close".to_string());
+        }
+    }
+
+    pub fn respond(&mut self, mut res: Response) -> io::Result<()> {
+        self.set_connection_headers(&mut res);
+        let bytes = match self.head_only {
+            true => res.head_request_bytes(),
+            false => res.to_bytes()
+        };
+        return self.writer().write_all(&bytes);
+    }
+
+    /// Writes only the head of a response. The caller streams the body after it.
+    pub fn respond_head(&mut self, mut res: Response) -> io::Result<()> {
+        self.set_connection_headers(&mut res);
+        return self.writer().write_all(&res.head_bytes());
+    }
+}
+
+/// Copies bytes one way until the reading side closes. A read timeout only ends the
+/// pump once neither direction has moved anything for `idle_timeout`.
+fn pump<R: Read, W: Write>(from: &mut R, to: &mut W, last_activity: &AtomicU64, started: Instant, idle_timeout: Duration) -> io::Result<()> {
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        match from.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => {
+                to.write_all(&buf[..n])?;
+                to.flush()?;
+                last_activity.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+            },
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {
+                let idle = (started.elapsed().as_millis() as u64).saturating_sub(last_activity.load(Ordering::Relaxed));
+                if idle >= idle_timeout.as_millis() as u64 {
+                    return Err(err);
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err)
+        }
+    }
+}
+
+/// Joins the client and upstream sockets after a `101 Switching Protocols`, so
+/// websocket frames flow both ways untouched. A side closing is passed on as a
+/// half-close; an error or the idle timeout tears both sockets down.
+pub fn splice(client: &mut Connection, upstream: BufReader<Box<dyn Stream>>, idle_timeout: Duration) -> io::Result<()> {
+    client.keep_alive = false;
+    let started = Instant::now();
+    let last_activity = Arc::new(AtomicU64::new(0));
+    let client_socket = client.writer().try_clone_stream()?;
+    let upstream_socket = upstream.get_ref().try_clone_stream()?;
+    client_socket.set_read_timeout(Some(idle_timeout))?;
+    upstream_socket.set_read_timeout(Some(idle_timeout))?;
+
+    let downstream = {
+        let last_activity = Arc::clone(&last_activity);
+        let mut client_socket = client_socket.try_clone_stream()?;
+        let upstream_socket = upstream_socket.try_clone_stream()?;
+        let mut upstream = upstream;
+        thread::spawn(move || {
+            match pump(&mut upstream, &mut client_socket, &last_activity, started, idle_timeout) {
+                Ok(_) => { let _ = client_socket.shutdown(Shutdown::Write); },
+                Err(_) => {
+                    let _ = client_socket.shutdown(Shutdown::Both);
+                    let _ = upstream_socket.shutdown(Shutdown::Both);
+                }
+            }
+        })
+    };
+
+    let mut upstream_writer = upstream_socket.try_clone_stream()?;
+    match pump(&mut client.reader, &mut upstream_writer, &last_activity, started, idle_timeout) {
+        Ok(_) => { let _ = upstream_socket.shutdown(Shutdown::Write); },
+        Err(_) => {
+            let _ = client_socket.shutdown(Shutdown::Both);
+            let _ = upstream_socket.shutdown(Shutdown::Both);
+        }
+    }
+    let _ = downstream.join();
+    return Ok(());
+}
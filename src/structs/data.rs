@@ -1,7 +1,12 @@
-use std::sync::{Arc, Mutex};
-use std::{collections::HashMap, net::TcpStream, io::prelude::*};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}};
+use std::{cmp::Reverse, collections::HashMap, fmt, net::{TcpStream, ToSocketAddrs}, io::{self, BufReader, prelude::*}, time::{Duration, Instant}};
+use std::{collections::hash_map::{DefaultHasher, RandomState}, hash::{BuildHasher, Hash, Hasher}};
 
-use crate::structs::http::{Request, Response, split_path};
+use regex::Regex;
+use rustls::ClientConfig;
+
+use crate::{schema::{Field, Schema}, tls};
+use crate::structs::{connection::{Connection, Stream, splice}, http::{Request, Response, split_path, get_header, remove_header, copy_body, has_body, HOP_BY_HOP_HEADERS}};
 
 pub fn get_ro_from_mutex<T: Clone>(m: &Arc<Mutex<T>>) -> Option<T> {
     if let Ok(data) = m.lock() {
@@ -11,18 +16,100 @@ pub fn get_ro_from_mutex<T: Clone>(m: &Arc<Mutex<T>>) -> Option<T> {
     }
 }
 
-#[derive(Clone)]
+/// Copies the global state and the route tree together. Both are always locked
+/// global first, so a reload swapping them is never seen half done.
+pub fn get_ro_snapshot(global: &Arc<Mutex<GlobalState>>, tree: &Arc<Mutex<Node>>) -> Option<(GlobalState, Node)> {
+    let global = global.lock().ok()?;
+    let tree = tree.lock().ok()?;
+    return Some((global.clone(), tree.clone()));
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Tls {
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    /// Names the generated certificate is valid for when no cert/key pair is given.
+    pub hostnames: Vec<String>,
+    /// Where the generated dev CA is kept, so it only has to be trusted once.
+    pub ca_dir: String
+}
+
+impl Tls {
+    pub fn schema() -> Schema {
+        return Schema::Object(vec![
+            Field::new("cert", Schema::String),
+            Field::new("key", Schema::String),
+            Field::new("hostnames", Schema::array(Schema::String)),
+            Field::new("ca_dir", Schema::String)
+        ]);
+    }
+
+    pub fn from_json(value:serde_json::Value) -> Result<Self, String> {
+        let mut result = Tls {
+            cert: None,
+            key: None,
+            hostnames: vec!["localhost".to_string(), "127.0.0.1".to_string()],
+            ca_dir: ".devsync".to_string()
+        };
+        for (field, target) in [("cert", &mut result.cert), ("key", &mut result.key)] {
+            if let Some(path) = value.get(field) {
+                match path.as_str() {
+                    Some(path) => *target = Some(path.to_string()),
+                    None => return Err(format!("TLS {} must be string", field))
+                }
+            }
+        }
+        if result.cert.is_some() != result.key.is_some() {
+            return Err("TLS cert and key must be given together".to_string());
+        }
+        if let Some(hostnames) = value.get("hostnames") {
+            match hostnames.as_array() {
+                Some(hostnames) => {
+                    result.hostnames = Vec::new();
+                    for hostname in hostnames {
+                        match hostname.as_str() {
+                            Some(hostname) => result.hostnames.push(hostname.to_string()),
+                            None => return Err("TLS hostnames must be strings".to_string())
+                        }
+                    }
+                },
+                None => return Err("TLS hostnames must be an array".to_string())
+            }
+        }
+        if let Some(ca_dir) = value.get("ca_dir") {
+            match ca_dir.as_str() {
+                Some(ca_dir) => result.ca_dir = ca_dir.to_string(),
+                None => return Err("TLS ca_dir must be string".to_string())
+            }
+        }
+        return Ok(result);
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub struct Exposed {
     pub host: String,
-    pub port: i64
+    pub port: i64,
+    /// Seconds an idle keep-alive connection is held open before it is dropped.
+    pub idle_timeout: u64,
+    pub tls: Option<Tls>
 }
 
 impl Exposed {
+    pub fn schema() -> Schema {
+        return Schema::Object(vec![
+            Field::new("host", Schema::String),
+            Field::new("port", Schema::Integer),
+            Field::new("idle_timeout", Schema::Integer),
+            Field::new("tls", Tls::schema())
+        ]);
+    }
+
     pub fn stringify(&self) -> String {
         return format!("{}:{}", self.host, self.port);
     }
     pub fn from_json(value:serde_json::Value) -> Result<Self, String> {
-        let mut result = Exposed { host: "0.0.0.0".to_string(), port: 3000 };
+        let mut result = Exposed { host: "0.0.0.0".to_string(), port: 3000, idle_timeout: 5, tls: None };
         if let Some(host) = value.get("host") {
             if let Some(host) = host.as_str() {
                 result.host = host.to_string();
@@ -37,6 +124,16 @@ impl Exposed {
                 return Err("Port must be string".to_string());
             }
         }
+        if let Some(idle_timeout) = value.get("idle_timeout") {
+            if let Some(idle_timeout) = idle_timeout.as_u64() {
+                result.idle_timeout = idle_timeout;
+            } else {
+                return Err("Idle timeout must be a positive number".to_string());
+            }
+        }
+        if let Some(tls) = value.get("tls") {
+            result.tls = Some(Tls::from_json(tls.clone())?);
+        }
         return Ok(result);
     }
 }
@@ -51,15 +148,424 @@ impl GlobalState {
     pub fn blank() -> Self {
         return GlobalState { services: HashMap::new(), exposed: None }
     }
+
+    /// State of every service instance, as served by the `health` component.
+    pub fn health_report(&self) -> serde_json::Value {
+        let mut names: Vec<&String> = self.services.keys().collect();
+        names.sort();
+        let services: serde_json::Map<String, serde_json::Value> = names.into_iter().map(|name| {
+            let instances: Vec<serde_json::Value> = self.services[name].instances().iter().map(|instance| {
+                let health = instance.health();
+                let mut report = serde_json::json!({
+                    "address": instance.address(),
+                    "health": match health {
+                        Health::Unknown => "unknown",
+                        Health::Healthy => "healthy",
+                        Health::Unhealthy(_) => "unhealthy"
+                    },
+                    "down": !instance.is_up(),
+                    "active": instance.active()
+                });
+                if let Health::Unhealthy(reason) = health {
+                    report["reason"] = serde_json::Value::String(reason);
+                }
+                report
+            }).collect();
+            (name.to_string(), serde_json::Value::Array(instances))
+        }).collect();
+        return serde_json::json!({ "services": services });
+    }
+}
+
+/// How to reach an `https` service.
+#[derive(Clone)]
+pub struct UpstreamTls {
+    /// Sent as SNI and checked against the upstream certificate.
+    pub server_name: String,
+    pub config: Arc<ClientConfig>
+}
+
+impl UpstreamTls {
+    pub fn schema() -> Schema {
+        return Schema::Object(vec![
+            Field::new("server_name", Schema::String),
+            Field::new("ca", Schema::String),
+            Field::new("insecure", Schema::Boolean)
+        ]);
+    }
+
+    pub fn from_json(value:serde_json::Value, host: &str) -> Result<Self, String> {
+        let server_name = match value.get("server_name") {
+            Some(server_name) => match server_name.as_str() {
+                Some(server_name) => server_name.to_string(),
+                None => return Err("Service TLS server_name must be string".to_string())
+            },
+            None => host.to_string()
+        };
+        let ca = match value.get("ca") {
+            Some(ca) => match ca.as_str() {
+                Some(ca) => Some(ca),
+                None => return Err("Service TLS ca must be string".to_string())
+            },
+            None => None
+        };
+        let insecure = match value.get("insecure") {
+            Some(insecure) => match insecure.as_bool() {
+                Some(insecure) => insecure,
+                None => return Err("Service TLS insecure must be boolean".to_string())
+            },
+            None => false
+        };
+        return Ok(UpstreamTls { server_name, config: tls::client_config(ca, insecure)? });
+    }
+
+    /// `Host` for requests to an instance on `port`: the client's names this proxy,
+    /// while the upstream expects the name its certificate is for.
+    pub fn host_header(&self, port: i64) -> String {
+        return match port {
+            443 => self.server_name.clone(),
+            port => format!("{}:{}", self.server_name, port)
+        };
+    }
+}
+
+/// What the last active health check said about an instance.
+#[derive(Clone, PartialEq)]
+pub enum Health {
+    Unknown,
+    Healthy,
+    Unhealthy(String)
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Health::Unknown => write!(f, "unknown"),
+            Health::Healthy => write!(f, "healthy"),
+            Health::Unhealthy(reason) => write!(f, "unhealthy ({})", reason)
+        };
+    }
+}
+
+/// One address a service runs on. Clones of a service share their instances,
+/// so connection counts, down marks and health carry over between requests.
+pub struct Instance {
+    pub host: String,
+    pub port: i64,
+    /// Exchanges currently open against this instance.
+    active: AtomicUsize,
+    /// Set when a connection is refused; the instance is skipped until then.
+    down_until: Mutex<Option<Instant>>,
+    health: Mutex<Health>,
+    last_check: Mutex<Option<Instant>>,
+    checking: AtomicBool
+}
+
+impl Instance {
+    fn new(host: String, port: i64) -> Self {
+        return Instance {
+            host,
+            port,
+            active: AtomicUsize::new(0),
+            down_until: Mutex::new(None),
+            health: Mutex::new(Health::Unknown),
+            last_check: Mutex::new(None),
+            checking: AtomicBool::new(false)
+        };
+    }
+
+    pub fn active(&self) -> usize {
+        return self.active.load(Ordering::Relaxed);
+    }
+
+    pub fn health(&self) -> Health {
+        return self.health.lock().unwrap_or_else(|err| err.into_inner()).clone();
+    }
+
+    /// Claims the next health check when `interval` has passed since the last
+    /// one and none is running.
+    pub fn start_check(&self, interval: Duration) -> bool {
+        let mut last_check = self.last_check.lock().unwrap_or_else(|err| err.into_inner());
+        if last_check.is_some_and(|last_check| last_check.elapsed() < interval) {
+            return false;
+        }
+        if self.checking.swap(true, Ordering::Relaxed) {
+            return false;
+        }
+        *last_check = Some(Instant::now());
+        return true;
+    }
+
+    /// Stores a check result and tells whether the health changed.
+    pub fn finish_check(&self, health: Health) -> bool {
+        if health == Health::Healthy {
+            self.mark_up();
+        }
+        let mut current = self.health.lock().unwrap_or_else(|err| err.into_inner());
+        let changed = *current != health;
+        *current = health;
+        self.checking.store(false, Ordering::Relaxed);
+        return changed;
+    }
+
+    pub fn address(&self) -> String {
+        return format!("{}:{}", self.host, self.port);
+    }
+
+    pub fn is_up(&self) -> bool {
+        return match *self.down_until.lock().unwrap_or_else(|err| err.into_inner()) {
+            Some(until) => Instant::now() >= until,
+            None => true
+        };
+    }
+
+    fn mark_down(&self, period: Duration) {
+        if self.is_up() {
+            println!("Service instance {} is down", self.address());
+        }
+        *self.down_until.lock().unwrap_or_else(|err| err.into_inner()) = Some(Instant::now() + period);
+    }
+
+    fn mark_up(&self) {
+        *self.down_until.lock().unwrap_or_else(|err| err.into_inner()) = None;
+    }
+}
+
+/// Counts an exchange against its instance for least-connections until dropped.
+pub struct InstanceLease {
+    instance: Arc<Instance>
+}
+
+impl InstanceLease {
+    fn new(instance: Arc<Instance>) -> Self {
+        instance.active.fetch_add(1, Ordering::Relaxed);
+        return InstanceLease { instance };
+    }
+}
+
+impl Drop for InstanceLease {
+    fn drop(&mut self) {
+        self.instance.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// How a service with several instances picks one for each exchange.
+#[derive(Clone)]
+pub enum Balance {
+    RoundRobin,
+    LeastConnections,
+    Random,
+    /// Requests with the same header, cookie or query value go to the same
+    /// instance, and only move when that instance goes down.
+    Hash(GuardSource)
+}
+
+impl Balance {
+    pub fn schema() -> Schema {
+        return Schema::OneOf(vec![
+            Schema::strings(&["round_robin", "least_connections", "random"]),
+            Schema::Object(vec![Field::required("hash", Schema::Object(GuardSource::fields()))])
+        ]);
+    }
+
+    pub fn from_json(value: &serde_json::Value) -> Result<Self, String> {
+        return match value {
+            serde_json::Value::String(name) => match name.as_str() {
+                "round_robin" => Ok(Balance::RoundRobin),
+                "least_connections" => Ok(Balance::LeastConnections),
+                "random" => Ok(Balance::Random),
+                _ => Err("Service balance must be round_robin, least_connections, random or a hash".to_string())
+            },
+            _ => match value.get("hash") {
+                Some(hash) => Ok(Balance::Hash(GuardSource::from_json(hash, "Service balance hash")?)),
+                None => Err("Service balance must be round_robin, least_connections, random or a hash".to_string())
+            }
+        };
+    }
+}
+
+/// An active check run against every instance of a service on an interval.
+#[derive(Clone)]
+pub struct HealthCheck {
+    /// Path for an HTTP GET; without one the check only opens a TCP connection.
+    pub path: Option<String>,
+    /// Status the HTTP check expects, any 2xx or 3xx when not set.
+    pub status: Option<i64>,
+    pub interval: Duration,
+    pub timeout: Duration
+}
+
+impl HealthCheck {
+    pub fn schema() -> Schema {
+        return Schema::Object(vec![
+            Field::new("type", Schema::strings(&["tcp", "http"])),
+            Field::new("path", Schema::String),
+            Field::new("status", Schema::Integer),
+            Field::new("interval", Schema::Number),
+            Field::new("timeout", Schema::Number)
+        ]);
+    }
+
+    /// A plain TCP connect, which is also what readiness means for services without a check.
+    pub fn tcp() -> Self {
+        return HealthCheck { path: None, status: None, interval: Duration::from_secs(5), timeout: Duration::from_secs(2) };
+    }
+
+    pub fn from_json(value: &serde_json::Value) -> Result<Self, String> {
+        let mut result = HealthCheck::tcp();
+        let http = match value.get("type") {
+            Some(check_type) => match check_type.as_str() {
+                Some("tcp") => false,
+                Some("http") => true,
+                _ => return Err("Service health type must be either tcp or http".to_string())
+            },
+            None => value.get("path").is_some()
+        };
+        if http {
+            result.path = match value.get("path") {
+                Some(path) => match path.as_str() {
+                    Some(path) => Some(format!("/{}", path.trim_start_matches("/"))),
+                    None => return Err("Service health path must be string".to_string())
+                },
+                None => Some("/".to_string())
+            };
+        }
+        if let Some(status) = value.get("status") {
+            match status.as_i64() {
+                Some(status) => result.status = Some(status),
+                None => return Err("Service health status must be a number".to_string())
+            }
+        }
+        for (field, target) in [("interval", &mut result.interval), ("timeout", &mut result.timeout)] {
+            if let Some(seconds) = value.get(field) {
+                match seconds.as_f64() {
+                    Some(seconds) if seconds > 0.0 => *target = Duration::from_secs_f64(seconds),
+                    _ => return Err(format!("Service health {} must be a positive number", field))
+                }
+            }
+        }
+        return Ok(result);
+    }
+}
+
+/// When a supervised service process is started again after it exits.
+#[derive(Clone, PartialEq)]
+pub enum Restart {
+    Never,
+    OnFailure,
+    Always
+}
+
+/// How to launch the process behind a service.
+#[derive(Clone, PartialEq)]
+pub struct ProcessSpec {
+    /// Program followed by its arguments. A string command runs through the shell.
+    pub command: Vec<String>,
+    pub cwd: Option<String>,
+    pub env: HashMap<String, String>,
+    pub restart: Restart
+}
+
+impl ProcessSpec {
+    /// Fields of the service object the process is read from.
+    pub fn fields() -> Vec<Field> {
+        return vec![
+            Field::new("command", Schema::OneOf(vec![Schema::String, Schema::array(Schema::String)])),
+            Field::new("cwd", Schema::String),
+            Field::new("env", Schema::map(Schema::OneOf(vec![Schema::String, Schema::Number, Schema::Boolean]))),
+            Field::new("restart", Schema::strings(&["never", "on_failure", "always"]))
+        ];
+    }
+
+    pub fn from_json(data: &serde_json::Map<String, serde_json::Value>, command: &serde_json::Value) -> Result<Self, String> {
+        let command = match command {
+            serde_json::Value::String(command) => match cfg!(windows) {
+                true => vec!["cmd".to_string(), "/C".to_string(), command.to_string()],
+                false => vec!["sh".to_string(), "-c".to_string(), command.to_string()]
+            },
+            serde_json::Value::Array(parts) if !parts.is_empty() => {
+                let mut command: Vec<String> = Vec::new();
+                for part in parts {
+                    match part.as_str() {
+                        Some(part) => command.push(part.to_string()),
+                        None => return Err("Service command parts must be strings".to_string())
+                    }
+                }
+                command
+            },
+            _ => return Err("Service command must be a string or a non-empty array".to_string())
+        };
+        let cwd = match data.get("cwd") {
+            Some(cwd) => match cwd.as_str() {
+                Some(cwd) => Some(cwd.to_string()),
+                None => return Err("Service cwd must be string".to_string())
+            },
+            None => None
+        };
+        let mut env: HashMap<String, String> = HashMap::new();
+        if let Some(vars) = data.get("env") {
+            match vars.as_object() {
+                Some(vars) => for (key, value) in vars {
+                    match value {
+                        serde_json::Value::String(value) => env.insert(key.to_string(), value.to_string()),
+                        serde_json::Value::Number(value) => env.insert(key.to_string(), value.to_string()),
+                        serde_json::Value::Bool(value) => env.insert(key.to_string(), value.to_string()),
+                        _ => return Err("Service env values must be strings".to_string())
+                    };
+                },
+                None => return Err("Service env must be an object".to_string())
+            }
+        }
+        let restart = match data.get("restart") {
+            Some(restart) => match restart.as_str() {
+                Some("never") => Restart::Never,
+                Some("on_failure") => Restart::OnFailure,
+                Some("always") => Restart::Always,
+                _ => return Err("Service restart must be never, on_failure or always".to_string())
+            },
+            None => Restart::OnFailure
+        };
+        return Ok(ProcessSpec { command, cwd, env, restart });
+    }
 }
 
 #[derive(Clone)]
 pub struct Service {
-    host: String,
-    port: i64
+    instances: Arc<Vec<Arc<Instance>>>,
+    balance: Balance,
+    /// Where round-robin continues from.
+    next: Arc<AtomicUsize>,
+    /// How long an instance that refused a connection is left out.
+    down_for: Duration,
+    health: Option<HealthCheck>,
+    process: Option<ProcessSpec>,
+    /// Services that have to be ready before this one's process starts.
+    depends_on: Vec<String>,
+    tls: Option<UpstreamTls>
 }
 
 impl Service {
+    pub fn schema() -> Schema {
+        let mut fields = vec![
+            Field::new("name", Schema::String),
+            Field::new("host", Schema::String),
+            Field::new("port", Schema::Integer),
+            Field::new("scheme", Schema::strings(&["http", "https"])),
+            Field::new("tls", UpstreamTls::schema()),
+            Field::new("instances", Schema::array(Schema::Object(vec![
+                Field::new("host", Schema::String),
+                Field::new("port", Schema::Integer)
+            ]))),
+            Field::new("balance", Balance::schema()),
+            Field::new("down_for", Schema::Integer),
+            Field::new("health", HealthCheck::schema()),
+            Field::new("depends_on", Schema::array(Schema::String)),
+            Field::new("enabled", Schema::Boolean)
+        ];
+        fields.extend(ProcessSpec::fields());
+        return Schema::Object(fields);
+    }
+
     pub fn from_val(val:serde_json::Value) -> Result<Self, String> {
         let data = match val.as_object() {
             Some(data) => data,
@@ -72,27 +578,300 @@ impl Service {
             },
             None => "localhost".to_string()
         };
+        let https = match data.get("scheme") {
+            Some(scheme) => match scheme.as_str() {
+                Some("http") => false,
+                Some("https") => true,
+                _ => return Err("Service scheme must be either http or https".to_string())
+            },
+            None => data.contains_key("tls")
+        };
         let port = match data.get("port") {
             Some(port) => match port.as_i64() {
                 Some(port) => port,
                 None => return Err("Service port must be type of number".to_string())
             },
+            None if https => 443,
             None => 3000
         };
-        return Ok(Service { host, port });
+        let mut instances: Vec<Arc<Instance>> = Vec::new();
+        match data.get("instances") {
+            Some(list) => match list.as_array() {
+                Some(list) if !list.is_empty() => for instance in list {
+                    let instance_host = match instance.get("host") {
+                        Some(instance_host) => match instance_host.as_str() {
+                            Some(instance_host) => instance_host.to_string(),
+                            None => return Err("Service instance host must be type of string".to_string())
+                        },
+                        None => host.clone()
+                    };
+                    let instance_port = match instance.get("port") {
+                        Some(instance_port) => match instance_port.as_i64() {
+                            Some(instance_port) => instance_port,
+                            None => return Err("Service instance port must be type of number".to_string())
+                        },
+                        None => port
+                    };
+                    instances.push(Arc::new(Instance::new(instance_host, instance_port)));
+                },
+                _ => return Err("Service instances must be a non-empty array".to_string())
+            },
+            None => instances.push(Arc::new(Instance::new(host.clone(), port)))
+        }
+        let balance = match data.get("balance") {
+            Some(balance) => Balance::from_json(balance)?,
+            None => Balance::RoundRobin
+        };
+        let down_for = match data.get("down_for") {
+            Some(down_for) => match down_for.as_u64() {
+                Some(down_for) => Duration::from_secs(down_for),
+                None => return Err("Service down_for must be a positive number".to_string())
+            },
+            None => Duration::from_secs(10)
+        };
+        let health = match data.get("health") {
+            Some(health) => Some(HealthCheck::from_json(health)?),
+            None => None
+        };
+        let process = match data.get("command") {
+            Some(command) => Some(ProcessSpec::from_json(data, command)?),
+            None => None
+        };
+        let mut depends_on: Vec<String> = Vec::new();
+        if let Some(list) = data.get("depends_on") {
+            match list.as_array() {
+                Some(list) => for dependency in list {
+                    match dependency.as_str() {
+                        Some(dependency) => depends_on.push(dependency.to_string()),
+                        None => return Err("Service depends_on must list service names".to_string())
+                    }
+                },
+                None => return Err("Service depends_on must be an array".to_string())
+            }
+        }
+        let tls = match https {
+            true => Some(UpstreamTls::from_json(data.get("tls").cloned().unwrap_or_default(), &host)?),
+            false => None
+        };
+        return Ok(Service { instances: Arc::new(instances), balance, next: Arc::new(AtomicUsize::new(0)), down_for, health, process, depends_on, tls });
     }
 
-    pub fn fetch(&self, req: Request) -> Response {
-        let con = TcpStream::connect(format!("{}:{}", self.host, self.port));
-        let mut con = match con {
+    pub fn instances(&self) -> &[Arc<Instance>] {
+        return &self.instances;
+    }
+
+    pub fn health_check(&self) -> Option<&HealthCheck> {
+        return self.health.as_ref();
+    }
+
+    /// Keeps the state of instances still at the same address, so a reload
+    /// does not forget connection counts, down marks and health.
+    pub fn carry_over(&mut self, old: &Service) {
+        let instances: Vec<Arc<Instance>> = self.instances.iter()
+            .map(|instance| old.instances.iter()
+                .find(|old_instance| old_instance.address() == instance.address())
+                .cloned()
+                .unwrap_or_else(|| Arc::clone(instance)))
+            .collect();
+        self.instances = Arc::new(instances);
+        self.next = Arc::clone(&old.next);
+    }
+
+    pub fn process(&self) -> Option<&ProcessSpec> {
+        return self.process.as_ref();
+    }
+
+    pub fn depends_on(&self) -> &[String] {
+        return &self.depends_on;
+    }
+
+    /// Whether any instance passes the health check, or accepts a connection
+    /// when the service has no check.
+    pub fn is_ready(&self) -> bool {
+        return self.instances.iter().any(|instance| self.check(instance).is_ok());
+    }
+
+    /// Instances to try for `req`, best first. Instances that failed their health
+    /// check are left out. Ones marked down by a refused connection come last, so
+    /// a request still gets through when every instance was marked down.
+    fn candidates(&self, req: &Request) -> Vec<Arc<Instance>> {
+        let (mut up, down): (Vec<Arc<Instance>>, Vec<Arc<Instance>>) = self.instances.iter()
+            .filter(|instance| !matches!(instance.health(), Health::Unhealthy(_)))
+            .cloned()
+            .partition(|instance| instance.is_up());
+        if !up.is_empty() {
+            let start = match &self.balance {
+                Balance::Random => RandomState::new().build_hasher().finish() as usize,
+                _ => self.next.fetch_add(1, Ordering::Relaxed)
+            };
+            let len = up.len();
+            up.rotate_left(start % len);
+            match &self.balance {
+                Balance::LeastConnections => up.sort_by_key(|instance| instance.active.load(Ordering::Relaxed)),
+                // Rendezvous hashing: each key ranks every instance, so losing one only moves its own keys.
+                Balance::Hash(source) => if let Some(key) = source.value(req) {
+                    up.sort_by_key(|instance| {
+                        let mut hasher = DefaultHasher::new();
+                        (&key, instance.address()).hash(&mut hasher);
+                        Reverse(hasher.finish())
+                    });
+                },
+                _ => {}
+            }
+        }
+        up.extend(down);
+        return up;
+    }
+
+    /// Opens a fresh connection to an instance of the service, doing the TLS handshake
+    /// for `https` ones. An instance refusing the connection is marked down and the next is tried.
+    pub fn connect(&self, req: &Request) -> io::Result<(Box<dyn Stream>, InstanceLease)> {
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "Service has no healthy instances");
+        for instance in self.candidates(req) {
+            let sock = match TcpStream::connect(instance.address()) {
+                Ok(sock) => sock,
+                Err(err) => {
+                    instance.mark_down(self.down_for);
+                    last_err = err;
+                    continue;
+                }
+            };
+            instance.mark_up();
+            let lease = InstanceLease::new(instance);
+            return match &self.tls {
+                Some(upstream_tls) => Ok((Box::new(tls::connect(Arc::clone(&upstream_tls.config), &upstream_tls.server_name, sock)?), lease)),
+                None => Ok((Box::new(sock), lease))
+            };
+        }
+        return Err(last_err);
+    }
+
+    /// Points `Host` at the instance behind `lease` for `https` services.
+    fn set_upstream_host(&self, req: &mut Request, lease: &InstanceLease) {
+        if let Some(upstream_tls) = &self.tls {
+            remove_header(&mut req.headers, "Host");
+            req.headers.insert("Host".to_string(), upstream_tls.host_header(lease.instance.port));
+        }
+    }
+
+    /// Answer for a request the service could not be reached for.
+    fn unreachable(err: &io::Error) -> Response {
+        return match err.kind() {
+            io::ErrorKind::NotFound => Response::service_unavailable(),
+            _ => Response::bad_gateway()
+        };
+    }
+
+    /// Runs the service's health check against one instance, a TCP connect when it has none.
+    pub fn check(&self, instance: &Instance) -> Result<(), String> {
+        let tcp = HealthCheck::tcp();
+        let check = self.health.as_ref().unwrap_or(&tcp);
+        let addr = match instance.address().to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+            Some(addr) => addr,
+            None => return Err(format!("Could not resolve {}", instance.address()))
+        };
+        let sock = TcpStream::connect_timeout(&addr, check.timeout).map_err(|err| err.to_string())?;
+        let path = match &check.path {
+            Some(path) => path,
+            None => return Ok(())
+        };
+        sock.set_read_timeout(Some(check.timeout)).map_err(|err| err.to_string())?;
+        sock.set_write_timeout(Some(check.timeout)).map_err(|err| err.to_string())?;
+        let mut con: Box<dyn Stream> = match &self.tls {
+            Some(upstream_tls) => Box::new(tls::connect(Arc::clone(&upstream_tls.config), &upstream_tls.server_name, sock).map_err(|err| err.to_string())?),
+            None => Box::new(sock)
+        };
+        let host = match &self.tls {
+            Some(upstream_tls) => upstream_tls.host_header(instance.port),
+            None => instance.address()
+        };
+        let req = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host);
+        con.write_all(req.as_bytes()).map_err(|err| err.to_string())?;
+        let res = match Response::read_head(&mut BufReader::new(con)) {
+            Some(res) => res,
+            None => return Err("No HTTP response".to_string())
+        };
+        return match check.status {
+            Some(status) if res.status_code != status => Err(format!("Status {} instead of {}", res.status_code, status)),
+            None if !(200..400).contains(&res.status_code) => Err(format!("Status {}", res.status_code)),
+            _ => Ok(())
+        };
+    }
+
+    pub fn fetch(&self, mut req: Request) -> Response {
+        let (mut con, lease) = match self.connect(&req) {
             Ok(con) => con,
-            Err(_) => return Response { status_code: 500, status: "ServerError".to_string(), headers: HashMap::new(), body: String::new() }
+            Err(err) => return Service::unreachable(&err)
         };
-        con.write_all(req.stringify().as_bytes()).unwrap();
+        self.set_upstream_host(&mut req, &lease);
+        // Every fetch opens its own upstream connection, so it is closed right after.
+        HOP_BY_HOP_HEADERS.iter().for_each(|name| remove_header(&mut req.headers, name));
+        req.headers.insert("Connection".to_string(), "close".to_string());
+        if con.write_all(&req.to_bytes()).is_err() {
+            return Response::server_error();
+        }
         return match Response::from_stream(con) {
             Some(resp) => resp,
-            None => Response { status_code: 500, status: "ServerError".to_string(), headers: HashMap::new(), body: String::new() }
+            None => Response::server_error()
+        }
+    }
+
+    /// Proxies a request whose body is still on the client connection, relaying
+    /// both bodies chunk by chunk instead of holding them in memory. Upgrade
+    /// requests that upstream accepts become a tunnel idling out after `upgrade_idle_timeout`.
+    pub fn stream(&self, mut req: Request, client: &mut Connection, upgrade_idle_timeout: Duration) -> io::Result<()> {
+        let (con, lease) = match self.connect(&req) {
+            Ok(con) => con,
+            Err(err) => {
+                client.read_body(&mut req)?;
+                return client.respond(Service::unreachable(&err));
+            }
+        };
+        self.set_upstream_host(&mut req, &lease);
+        let head_only = req.method == "HEAD";
+        let expects_continue = get_header(&req.headers, "Expect")
+            .map(|value| value.eq_ignore_ascii_case("100-continue"))
+            .unwrap_or(false);
+        let upgrade = req.upgrade().cloned();
+        remove_header(&mut req.headers, "Expect");
+        HOP_BY_HOP_HEADERS.iter().for_each(|name| remove_header(&mut req.headers, name));
+        if let Some(upgrade) = &upgrade {
+            req.headers.insert("Connection".to_string(), "Upgrade".to_string());
+            req.headers.insert("Upgrade".to_string(), upgrade.to_string());
+        } else {
+            req.headers.insert("Connection".to_string(), "close".to_string());
+        }
+
+        let mut upstream_writer = con.try_clone_stream()?;
+        upstream_writer.write_all(&req.head_bytes())?;
+        if expects_continue {
+            client.writer().write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        }
+        copy_body(&mut client.reader, &mut upstream_writer, &req.headers, false)?;
+
+        let mut upstream = BufReader::new(con);
+        let res = loop {
+            match Response::read_head(&mut upstream) {
+                // Interim responses are dropped, the client already got its 100 Continue.
+                Some(res) if (100..200).contains(&res.status_code) && res.status_code != 101 => continue,
+                Some(res) => break res,
+                None => return client.respond(Response::server_error())
+            }
+        };
+        if upgrade.is_some() && res.status_code == 101 {
+            client.writer().write_all(&res.head_bytes())?;
+            return splice(client, upstream, upgrade_idle_timeout);
+        }
+        if res.is_close_delimited() {
+            client.keep_alive = false;
+        }
+        let status_code = res.status_code;
+        let headers = res.headers.clone();
+        client.respond_head(res)?;
+        if !head_only && has_body(status_code) {
+            copy_body(&mut upstream, client.writer(), &headers, true)?;
         }
+        return Ok(());
     }
 }
 
@@ -120,34 +899,313 @@ impl Clone for Box<dyn Component> {
     }
 }
 
+/// Why a request of the config could not be turned into a component. Components
+/// only know the field they choke on; `at` adds the index of the request.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    Missing { index: usize, field: &'static str },
+    Invalid { index: usize, field: &'static str, message: String },
+    UnknownType { index: usize, kind: String }
+}
+
+impl ConfigError {
+    pub fn missing(field: &'static str) -> Self {
+        return ConfigError::Missing { index: 0, field };
+    }
+
+    pub fn invalid(field: &'static str, message: &str) -> Self {
+        return ConfigError::Invalid { index: 0, field, message: message.to_string() };
+    }
+
+    pub fn at(self, index: usize) -> Self {
+        return match self {
+            ConfigError::Missing { field, .. } => ConfigError::Missing { index, field },
+            ConfigError::Invalid { field, message, .. } => ConfigError::Invalid { index, field, message },
+            ConfigError::UnknownType { kind, .. } => ConfigError::UnknownType { index, kind }
+        };
+    }
+
+    pub fn index(&self) -> usize {
+        return match self {
+            ConfigError::Missing { index, .. } | ConfigError::Invalid { index, .. } | ConfigError::UnknownType { index, .. } => *index
+        };
+    }
+
+    pub fn field(&self) -> &'static str {
+        return match self {
+            ConfigError::Missing { field, .. } | ConfigError::Invalid { field, .. } => field,
+            ConfigError::UnknownType { .. } => "type"
+        };
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            ConfigError::Missing { .. } => write!(f, "is required"),
+            ConfigError::Invalid { message, .. } => write!(f, "{}", message),
+            ConfigError::UnknownType { kind, .. } => write!(f, "unknown component type {}", kind)
+        };
+    }
+}
+
 pub trait Component: CloneComponent {
     fn call(&self, global: &GlobalState, req: Request) -> Response;
-    fn parse(val:serde_json::Value) -> Self where Self: Sized;
+    fn parse(val:serde_json::Value) -> Result<Self, ConfigError> where Self: Sized;
+    /// Config fields of the component, next to the route fields every request has.
+    fn fields() -> Vec<Field> where Self: Sized;
+
+    /// Answers a request straight on the client connection. The default reads the
+    /// whole body and writes back what `call` returns; streaming components override it.
+    fn serve(&self, global: &GlobalState, mut req: Request, client: &mut Connection) -> io::Result<()> {
+        client.read_body(&mut req)?;
+        let res = self.call(global, req);
+        return client.respond(res);
+    }
+}
+
+/// Where a guard looks for the value it tests.
+#[derive(Clone)]
+pub enum GuardSource {
+    Header(String),
+    Cookie(String),
+    Query(String)
 }
 
+#[derive(Clone)]
+pub enum GuardTest {
+    Present,
+    Equals(String),
+    Matches(Regex)
+}
+
+/// A condition a request must meet, on top of its path, to reach an endpoint.
+#[derive(Clone)]
+pub struct Guard {
+    pub source: GuardSource,
+    pub test: GuardTest
+}
+
+impl GuardSource {
+    pub fn fields() -> Vec<Field> {
+        return vec![
+            Field::new("header", Schema::String),
+            Field::new("cookie", Schema::String),
+            Field::new("query", Schema::String)
+        ];
+    }
+
+    /// Reads `{"header": name}`, `{"cookie": name}` or `{"query": name}`; `what` names the block in errors.
+    pub fn from_json(value: &serde_json::Value, what: &str) -> Result<Self, String> {
+        let source = match (value.get("header"), value.get("cookie"), value.get("query")) {
+            (Some(name), None, None) => name.as_str().map(|name| GuardSource::Header(name.to_string())),
+            (None, Some(name), None) => name.as_str().map(|name| GuardSource::Cookie(name.to_string())),
+            (None, None, Some(name)) => name.as_str().map(|name| GuardSource::Query(name.to_string())),
+            _ => return Err(format!("{} must have exactly one of header, cookie or query", what))
+        };
+        return match source {
+            Some(source) => Ok(source),
+            None => Err(format!("{} header, cookie or query name must be string", what))
+        };
+    }
+
+    pub fn value(&self, req: &Request) -> Option<String> {
+        return match self {
+            GuardSource::Header(name) => get_header(&req.headers, name).cloned(),
+            GuardSource::Cookie(name) => req.cookie(name),
+            GuardSource::Query(name) => req.query_param(name)
+        };
+    }
+}
+
+impl Guard {
+    pub fn schema() -> Schema {
+        let mut fields = GuardSource::fields();
+        fields.push(Field::new("equals", Schema::String));
+        fields.push(Field::new("matches", Schema::String));
+        return Schema::Object(fields);
+    }
+
+    pub fn from_json(value:serde_json::Value) -> Result<Self, String> {
+        let source = GuardSource::from_json(&value, "Condition")?;
+        let test = match (value.get("equals"), value.get("matches")) {
+            (None, None) => GuardTest::Present,
+            (Some(equals), None) => match equals.as_str() {
+                Some(equals) => GuardTest::Equals(equals.to_string()),
+                None => return Err("Condition equals must be string".to_string())
+            },
+            (None, Some(matches)) => match matches.as_str().map(Regex::new) {
+                Some(Ok(regex)) => GuardTest::Matches(regex),
+                Some(Err(err)) => return Err(format!("Invalid condition regex: {}", err)),
+                None => return Err("Condition matches must be string".to_string())
+            },
+            (Some(_), Some(_)) => return Err("Condition must have either equals or matches, not both".to_string())
+        };
+        return Ok(Guard { source, test });
+    }
+
+    pub fn check(&self, req: &Request) -> bool {
+        return match (&self.test, self.source.value(req)) {
+            (_, None) => false,
+            (GuardTest::Present, Some(_)) => true,
+            (GuardTest::Equals(expected), Some(value)) => *expected == value,
+            (GuardTest::Matches(regex), Some(value)) => regex.is_match(&value)
+        };
+    }
+}
+
+/// One request entry mounted on a route: its component and the requests it takes.
+#[derive(Clone)]
+pub struct Endpoint {
+    /// Upper-case methods the entry answers; empty means any method.
+    pub methods: Vec<String>,
+    /// Conditions that must all hold. Endpoints on one route are tried in
+    /// declaration order, so an unguarded fallback goes after the guarded ones.
+    pub guards: Vec<Guard>,
+    pub component: Box<dyn Component>
+}
+
+impl Endpoint {
+    pub fn matches_guards(&self, req: &Request) -> bool {
+        return self.guards.iter().all(|guard| guard.check(req));
+    }
+
+    /// `HEAD` is answered wherever `GET` is, as HTTP expects.
+    pub fn allows_method(&self, method: &str) -> bool {
+        return self.methods.is_empty()
+            || self.methods.iter().any(|allowed| allowed == method)
+            || (method == "HEAD" && self.methods.iter().any(|allowed| allowed == "GET"));
+    }
+
+    pub fn allowed_methods(&self) -> Vec<String> {
+        let mut methods = self.methods.clone();
+        if methods.iter().any(|method| method == "GET") && !methods.iter().any(|method| method == "HEAD") {
+            methods.push("HEAD".to_string());
+        }
+        return methods;
+    }
+}
+
+/// A component picked for a request path, with what the path segments matched.
+pub struct Route<'a> {
+    pub component: &'a dyn Component,
+    /// The part of the path below the matched prefix, passed on to the component.
+    pub remained: String,
+    pub params: HashMap<String, String>,
+    /// False when only a prefix of the path matched a route.
+    pub exact: bool
+}
+
+impl Route<'_> {
+    /// Path segments below the matched prefix; fewer means a deeper match.
+    fn segments_left(&self) -> usize {
+        return split_path(self.remained.clone()).len();
+    }
+}
+
+/// Where a request entry is mounted: a trie `uri` or a whole-path `uri_regex`.
+pub enum RoutePath {
+    Uri(String),
+    Regex(Regex)
+}
+
+/// Routing trie keyed by path segment. Besides literal segments, a route may use
+/// `:name` to capture one segment and a trailing `*name` to capture the rest.
+/// A literal segment beats a parameter, which beats a wildcard. A route that
+/// covers the whole path wins over one that only matches a prefix of it, except
+/// that a wildcard does not take a request away from a literal or parameter
+/// segment next to it that matched, even as a prefix. Among prefix matches the
+/// one covering the most segments wins.
+///
+/// Regex routes live on the root and are matched against the whole path. They
+/// are tried in declaration order after the trie found no exact match, but
+/// before falling back to a trie prefix match, so a catch-all `/` route does not
+/// shadow them. Named groups become params and the whole path is passed on.
+///
+/// Every place a route ends holds its endpoints in declaration order. The first
+/// one `accepts` lets through wins; a location where none does is skipped as if
+/// the route was not there.
+///
+/// The root also keeps a separate tree per `hosts` entry. `for_host` picks one
+/// by the `Host` header, and the root's own routes serve every other host.
 #[derive(Clone)]
 pub struct Node {
     pub next: HashMap<String, Node>,
-    pub value: Option<Box<dyn Component>>
+    /// `:name` children, tried in the order they were declared.
+    pub params: Vec<(String, Node)>,
+    pub wildcard: Option<(String, Vec<Endpoint>)>,
+    pub value: Vec<Endpoint>,
+    pub patterns: Vec<(Regex, Endpoint)>,
+    pub hosts: Vec<(String, Node)>
+}
+
+/// Drops the port from a `Host` header value, keeping IPv6 literals intact.
+pub fn host_name(host: &str) -> &str {
+    if host.starts_with("[") {
+        return host.split_once("]").map(|(name, _)| &host[..name.len() + 1]).unwrap_or(host);
+    }
+    return host.split_once(":").map(|(name, _)| name).unwrap_or(host);
+}
+
+/// `*.example.com` matches any subdomain of `example.com`, and a lone `*` matches every host.
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    return match pattern.strip_prefix("*.") {
+        Some(suffix) => host.len() > suffix.len() + 1
+            && host.to_ascii_lowercase().ends_with(&format!(".{}", suffix.to_ascii_lowercase())),
+        None => pattern.eq_ignore_ascii_case(host)
+    };
 }
 
 unsafe impl Send for Node {}
 
+type Accepts<'a> = dyn FnMut(&Endpoint) -> bool + 'a;
+
+fn pick<'a>(endpoints: &'a [Endpoint], accepts: &mut Accepts) -> Option<&'a Endpoint> {
+    return endpoints.iter().find(|endpoint| accepts(endpoint));
+}
+
 impl Node {
     pub fn new() -> Self {
         return Node {
             next: HashMap::new(),
-            value: None
+            params: Vec::new(),
+            wildcard: None,
+            value: Vec::new(),
+            patterns: Vec::new(),
+            hosts: Vec::new()
         };
     }
 
-    pub fn insert(&mut self, path:String, component:Box<dyn Component>) {
-        self.raw_insert(split_path(path), component);
+    /// The tree serving `host`: an exact host name first, then wildcard patterns
+    /// in declaration order, then the root itself as the default host.
+    pub fn for_host(&self, host: &str) -> &Node {
+        let host = host_name(host);
+        if let Some((_, tree)) = self.hosts.iter().find(|(pattern, _)| !pattern.contains("*") && host_matches(pattern, host)) {
+            return tree;
+        }
+        if let Some((_, tree)) = self.hosts.iter().find(|(pattern, _)| host_matches(pattern, host)) {
+            return tree;
+        }
+        return self;
+    }
+
+    pub fn insert(&mut self, path:String, endpoint:Endpoint) {
+        self.raw_insert(split_path(path), endpoint);
+    }
+
+    pub fn insert_route(&mut self, path:RoutePath, endpoint:Endpoint) {
+        match path {
+            RoutePath::Uri(uri) => self.insert(uri, endpoint),
+            RoutePath::Regex(regex) => self.patterns.push((regex, endpoint))
+        }
     }
 
-    pub fn raw_insert(&mut self, parts:Vec<String>, component:Box<dyn Component>) {
+    pub fn raw_insert(&mut self, parts:Vec<String>, endpoint:Endpoint) {
         if parts.is_empty() {
-            self.value = Some(component);
+            self.value.push(endpoint);
         } else {
             let current:String = parts[0].clone();
             let other_parts:Vec<String> = parts.iter()
@@ -155,34 +1213,201 @@ impl Node {
                 .filter(|&(i, _)| i != 0)
                 .map(|(_, el)| el.to_string())
                 .collect();
-            if let Some(ref mut next) = self.next.get_mut(&current) {
-                next.raw_insert(other_parts, component);
+            if let Some(name) = current.strip_prefix("*") {
+                match &mut self.wildcard {
+                    Some((_, endpoints)) => endpoints.push(endpoint),
+                    None => self.wildcard = Some((name.to_string(), vec![endpoint]))
+                }
+            } else if let Some(name) = current.strip_prefix(":") {
+                if let Some((_, next)) = self.params.iter_mut().find(|(param, _)| param == name) {
+                    next.raw_insert(other_parts, endpoint);
+                } else {
+                    let mut next = Node::new();
+                    next.raw_insert(other_parts, endpoint);
+                    self.params.push((name.to_string(), next));
+                }
+            } else if let Some(next) = self.next.get_mut(&current) {
+                next.raw_insert(other_parts, endpoint);
             } else {
                 let mut next = Node::new();
-                next.raw_insert(other_parts, component);
+                next.raw_insert(other_parts, endpoint);
                 self.next.insert(current, next);
             }
         }
     }
 
-    pub fn search(&self, path:String) -> (&Node, String) {
-        return self.raw_search(split_path(path));
+    pub fn search(&self, path:String, accepts:&mut Accepts) -> Option<Route<'_>> {
+        let route = self.raw_search(&split_path(path.clone()), accepts);
+        if route.as_ref().is_some_and(|route| route.exact) {
+            return route;
+        }
+        for (regex, endpoint) in &self.patterns {
+            if let Some(captures) = regex.captures(&path) {
+                if !accepts(endpoint) {
+                    continue;
+                }
+                let params: HashMap<String, String> = regex.capture_names()
+                    .flatten()
+                    .filter_map(|name| captures.name(name).map(|value| (name.to_string(), value.as_str().to_string())))
+                    .collect();
+                return Some(Route {
+                    component: endpoint.component.as_ref(),
+                    remained: path.trim_start_matches("/").to_string(),
+                    params,
+                    exact: true
+                });
+            }
+        }
+        return route;
+    }
+
+    /// Methods some route at `path` answers, for the `Allow` header of a 405.
+    /// Only endpoints whose guards `req` meets count.
+    pub fn allowed_methods(&self, path:String, req:&Request) -> Vec<String> {
+        let mut methods: Vec<String> = Vec::new();
+        self.search(path, &mut |endpoint| {
+            if endpoint.matches_guards(req) {
+                methods.extend(endpoint.allowed_methods());
+            }
+            false
+        });
+        methods.sort();
+        methods.dedup();
+        return methods;
     }
 
-    pub fn raw_search(&self, parts:Vec<String>) -> (&Node, String) {
-        if parts.is_empty() {
-            return (self, "".to_string());
+    /// Depth first, so a literal branch that dead-ends further down still
+    /// lets a parameter, wildcard or shorter prefix route take the request. A
+    /// prefix match is only kept as a fallback while the other branches are
+    /// tried for an exact one; the fallback with the fewest segments left
+    /// wins, and it beats the wildcard and prefix route of this node.
+    pub fn raw_search(&self, parts:&[String], accepts:&mut Accepts) -> Option<Route<'_>> {
+        let mut fallback: Option<Route<'_>> = None;
+        if let Some((current, other_parts)) = parts.split_first() {
+            if let Some(route) = self.next.get(current).and_then(|next| next.raw_search(other_parts, accepts)) {
+                if route.exact {
+                    return Some(route);
+                }
+                fallback = Some(route);
+            }
+            for (name, next) in &self.params {
+                if let Some(mut route) = next.raw_search(other_parts, accepts) {
+                    route.params.insert(name.to_string(), current.to_string());
+                    if route.exact {
+                        return Some(route);
+                    }
+                    if fallback.as_ref().is_none_or(|kept| route.segments_left() < kept.segments_left()) {
+                        fallback = Some(route);
+                    }
+                }
+            }
         }
-        let current:String = parts[0].clone();
-        let other_parts:Vec<String> = parts.iter()
-            .enumerate()
-            .filter(|&(i, _)| i != 0)
-            .map(|(_, el)| el.to_string())
-            .collect();
-        if let Some(ref next) = self.next.get(&current) {
-            return next.raw_search(other_parts);
+        if fallback.is_some() {
+            return fallback;
         }
-        return (self, parts.join("/"));
+        if let Some((name, endpoints)) = &self.wildcard {
+            if let Some(endpoint) = pick(endpoints, accepts) {
+                let mut params: HashMap<String, String> = HashMap::new();
+                params.insert(name.to_string(), parts.join("/"));
+                return Some(Route { component: endpoint.component.as_ref(), remained: parts.join("/"), params, exact: true });
+            }
+        }
+        return pick(&self.value, accepts).map(|endpoint| Route {
+            component: endpoint.component.as_ref(),
+            remained: parts.join("/"),
+            params: HashMap::new(),
+            exact: parts.is_empty()
+        });
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::parsing::parse_requests;
+
+    fn tree(requests: serde_json::Value) -> Node {
+        return parse_requests(requests, &GlobalState::empty()).unwrap();
+    }
+
+    /// Body of the mock that `path` is routed to.
+    fn routed(tree: &Node, path: &str) -> Option<String> {
+        let route = tree.search(path.to_string(), &mut |_| true)?;
+        let req = Request::read_head(&mut "GET / HTTP/1.1\r\n\r\n".as_bytes()).unwrap();
+        return Some(String::from_utf8(route.component.call(&GlobalState::empty(), req).body).unwrap());
+    }
+
+    #[test]
+    fn param_beats_prefix_of_a_literal_route() {
+        let tree = tree(json!([
+            {"type": "mock", "uri": "/api/", "body": "prefix"},
+            {"type": "mock", "uri": "/:v/users", "body": "param"}
+        ]));
+        assert_eq!(routed(&tree, "/api/users").as_deref(), Some("param"));
+        assert_eq!(routed(&tree, "/api/orders").as_deref(), Some("prefix"));
+    }
+
+    #[test]
+    fn literal_prefix_beats_wildcard() {
+        let tree = tree(json!([
+            {"type": "mock", "uri": "/static/", "body": "static"},
+            {"type": "mock", "uri": "/*rest", "body": "wildcard"}
+        ]));
+        assert_eq!(routed(&tree, "/static/app.js").as_deref(), Some("static"));
+        assert_eq!(routed(&tree, "/other/app.js").as_deref(), Some("wildcard"));
+    }
+
+    #[test]
+    fn deeper_prefix_beats_shorter_prefix() {
+        let tree = tree(json!([
+            {"type": "mock", "uri": "/", "body": "root"},
+            {"type": "mock", "uri": "/api/", "body": "api"},
+            {"type": "mock", "uri": "/:v/users/", "body": "param"}
+        ]));
+        assert_eq!(routed(&tree, "/api/orders/1").as_deref(), Some("api"));
+        assert_eq!(routed(&tree, "/api/users/x").as_deref(), Some("param"));
+        assert_eq!(routed(&tree, "/other").as_deref(), Some("root"));
+    }
+
+    #[test]
+    fn exact_trie_match_beats_regex() {
+        let tree = tree(json!([
+            {"type": "mock", "uri_regex": "/users/.*", "body": "regex"},
+            {"type": "mock", "uri": "/users/list", "body": "trie"}
+        ]));
+        assert_eq!(routed(&tree, "/users/list").as_deref(), Some("trie"));
+        assert_eq!(routed(&tree, "/users/12").as_deref(), Some("regex"));
+    }
+
+    #[test]
+    fn regex_beats_trie_prefix_match() {
+        let tree = tree(json!([
+            {"type": "mock", "uri": "/", "body": "root"},
+            {"type": "mock", "uri_regex": "/users/[0-9]+", "body": "regex"}
+        ]));
+        assert_eq!(routed(&tree, "/users/12").as_deref(), Some("regex"));
+        assert_eq!(routed(&tree, "/users/me").as_deref(), Some("root"));
+    }
+
+    #[test]
+    fn regex_routes_are_tried_in_declaration_order() {
+        let tree = tree(json!([
+            {"type": "mock", "uri_regex": "/files/.*", "body": "first"},
+            {"type": "mock", "uri_regex": "/files/[a-z]+", "body": "second"}
+        ]));
+        assert_eq!(routed(&tree, "/files/readme").as_deref(), Some("first"));
+    }
+
+    #[test]
+    fn regex_named_groups_become_params() {
+        let tree = tree(json!([
+            {"type": "mock", "uri_regex": "/users/(?P<id>[0-9]+)/posts/(?<post>[0-9]+)", "body": "post"}
+        ]));
+        let route = tree.search("/users/12/posts/3".to_string(), &mut |_| true).unwrap();
+        assert_eq!(route.params.get("id").map(|id| id.as_str()), Some("12"));
+        assert_eq!(route.params.get("post").map(|post| post.as_str()), Some("3"));
+        assert_eq!(route.remained, "users/12/posts/3");
+    }
+}
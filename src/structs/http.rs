@@ -1,4 +1,4 @@
-use std::{net::TcpStream, collections::HashMap, io::{BufReader, prelude::*}};
+use std::{collections::HashMap, io::{self, BufReader, prelude::*}};
 
 use std::path::Path;
 
@@ -26,74 +26,383 @@ pub fn merge_paths(a: &str, b: &str) -> String {
     return res.join("/");
 }
 
+/// Looks a header up ignoring the case of its name, as HTTP requires.
+pub fn get_header<'a>(headers: &'a HashMap<String, String>, name: &str) -> Option<&'a String> {
+    return headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value);
+}
+
+/// Reads one line of an HTTP head. Bytes that are not valid UTF-8 are replaced
+/// instead of failing the whole message, since only the body has to stay exact.
+fn read_head_line<R: BufRead>(buf: &mut R) -> Option<String> {
+    let mut line: Vec<u8> = Vec::new();
+    match buf.read_until(b'\n', &mut line) {
+        Ok(0) => None,
+        Ok(_) => Some(String::from_utf8_lossy(&line).to_string()),
+        Err(_) => None
+    }
+}
+
+fn read_headers<R: BufRead>(buf: &mut R) -> Option<HashMap<String, String>> {
+    let mut headers: HashMap<String, String> = HashMap::new();
+    loop {
+        let line = read_head_line(buf)?;
+        let line = line.trim();
+
+        if line.is_empty() {
+            break;
+        }
+        let (key, value) = line.split_once(":")?;
+        headers.insert(key.trim().to_string(), value.trim().to_string());
+    }
+    return Some(headers);
+}
+
+pub fn is_chunked(headers: &HashMap<String, String>) -> bool {
+    return match get_header(headers, "Transfer-Encoding") {
+        Some(encoding) => encoding
+            .split(",")
+            .last()
+            .map(|el| el.trim().eq_ignore_ascii_case("chunked"))
+            .unwrap_or(false),
+        None => false
+    };
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body, returning the joined chunks and
+/// the trailer fields that follow the last chunk.
+pub fn read_chunked<R: BufRead>(buf: &mut R) -> Option<(Vec<u8>, HashMap<String, String>)> {
+    let mut body: Vec<u8> = Vec::new();
+    loop {
+        let line = read_head_line(buf)?;
+        let size = line.split(";").next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).ok()?;
+        if size == 0 {
+            break;
+        }
+        // The size comes from the peer, so the chunk is read as it arrives
+        // instead of allocating whatever it claims up front.
+        let end = body.len().checked_add(size)?;
+        buf.take(size as u64).read_to_end(&mut body).ok()?;
+        if body.len() < end {
+            return None;
+        }
+        let mut crlf = [0u8; 2];
+        buf.read_exact(&mut crlf).ok()?;
+        if &crlf != b"\r\n" {
+            return None;
+        }
+    }
+    let trailers = read_headers(buf)?;
+    return Some((body, trailers));
+}
+
+/// Encodes a body as a single chunk followed by the terminating chunk and trailers.
+pub fn write_chunked(res: &mut Vec<u8>, body: &[u8], trailers: &HashMap<String, String>) {
+    if !body.is_empty() {
+        res.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+        res.extend_from_slice(body);
+        res.extend_from_slice(b"\r\n");
+    }
+    res.extend_from_slice(b"0\r\n");
+    trailers.iter().for_each(|(key, value)| {
+        res.extend_from_slice(format!("{}: {}\r\n", key, value).as_bytes());
+    });
+    res.extend_from_slice(b"\r\n");
+}
+
+/// Whether the body of a message with `headers` can be told apart from what
+/// follows it: chunked, a numeric `Content-Length` or no body at all.
+pub fn has_valid_framing(headers: &HashMap<String, String>) -> bool {
+    return is_chunked(headers) || get_header(headers, "Content-Length").is_none_or(|length| length.parse::<u64>().is_ok());
+}
+
+fn read_body<R: BufRead>(buf: &mut R, headers: &HashMap<String, String>) -> Option<(Vec<u8>, HashMap<String, String>)> {
+    if is_chunked(headers) {
+        return read_chunked(buf);
+    }
+    let content_length: u64 = match get_header(headers, "Content-Length") {
+        Some(length) => length.parse().ok()?,
+        None => 0
+    };
+    let mut body: Vec<u8> = Vec::new();
+    buf.take(content_length).read_to_end(&mut body).ok()?;
+    if (body.len() as u64) < content_length {
+        return None;
+    }
+    return Some((body, HashMap::new()));
+}
+
+fn read_raw_line<R: BufRead>(from: &mut R) -> io::Result<Vec<u8>> {
+    let mut line: Vec<u8> = Vec::new();
+    if from.read_until(b'\n', &mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    return Ok(line);
+}
+
+/// Relays a body framed as `headers` describe from `from` to `to` without holding
+/// it in memory. Chunked bodies keep their framing and trailers byte for byte.
+/// Blocking writes give backpressure: nothing more is read until `to` accepts it.
+pub fn copy_body<R: BufRead, W: Write>(from: &mut R, to: &mut W, headers: &HashMap<String, String>, until_eof: bool) -> io::Result<()> {
+    if is_chunked(headers) {
+        loop {
+            let line = read_raw_line(from)?;
+            to.write_all(&line)?;
+            let size = String::from_utf8_lossy(&line).split(";").next().unwrap_or_default().trim().to_string();
+            let size = u64::from_str_radix(&size, 16)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Bad chunk size"))?;
+            if size == 0 {
+                loop {
+                    let line = read_raw_line(from)?;
+                    to.write_all(&line)?;
+                    if line == b"\r\n" || line == b"\n" {
+                        break;
+                    }
+                }
+                break;
+            }
+            let copied = io::copy(&mut from.take(size + 2), to)?;
+            if copied < size + 2 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    } else if let Some(length) = get_header(headers, "Content-Length") {
+        let length: u64 = length.parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Bad Content-Length"))?;
+        let copied = io::copy(&mut from.take(length), to)?;
+        if copied < length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    } else if until_eof {
+        io::copy(from, to)?;
+    }
+    return to.flush();
+}
+
+/// Reason phrase for a status line, in the same style as `Response::not_found`.
+pub fn status_text(status_code: i64) -> &'static str {
+    return match status_code {
+        100 => "Continue",
+        101 => "SwitchingProtocols",
+        200 => "Ok",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "NoContent",
+        301 => "MovedPermanently",
+        302 => "Found",
+        303 => "SeeOther",
+        304 => "NotModified",
+        307 => "TemporaryRedirect",
+        308 => "PermanentRedirect",
+        400 => "BadRequest",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "NotFound",
+        405 => "MethodNotAllowed",
+        409 => "Conflict",
+        422 => "UnprocessableEntity",
+        429 => "TooManyRequests",
+        500 => "ServerError",
+        502 => "BadGateway",
+        503 => "ServiceUnavailable",
+        504 => "GatewayTimeout",
+        _ => "Status"
+    };
+}
+
+pub fn has_body(status_code: i64) -> bool {
+    return !((100..200).contains(&status_code) || status_code == 204 || status_code == 304);
+}
+
+fn write_head(res: &mut Vec<u8>, start_line: &str, headers: &HashMap<String, String>) {
+    res.extend_from_slice(start_line.as_bytes());
+    res.extend_from_slice(b"\r\n");
+    headers.iter().for_each(|(key, value)| {
+        res.extend_from_slice(format!("{}: {}\r\n", key, value).as_bytes());
+    });
+    res.extend_from_slice(b"\r\n");
+}
+
+/// Headers with the framing that fits `body`: a length unless the message is chunked.
+fn framed_headers(headers: &HashMap<String, String>, body: &[u8]) -> HashMap<String, String> {
+    let mut headers = headers.clone();
+    if is_chunked(&headers) {
+        remove_header(&mut headers, "Content-Length");
+    } else if get_header(&headers, "Content-Length").is_none() {
+        headers.insert("Content-Length".to_string(), body.len().to_string());
+    }
+    return headers;
+}
+
+fn write_message(res: &mut Vec<u8>, start_line: &str, headers: &HashMap<String, String>, body: &[u8], trailers: &HashMap<String, String>) {
+    let chunked = is_chunked(headers);
+    write_head(res, start_line, &framed_headers(headers, body));
+    if chunked {
+        write_chunked(res, body, trailers);
+    } else {
+        res.extend_from_slice(body);
+    }
+}
+
+/// Headers that describe a single connection and must not be forwarded.
+pub const HOP_BY_HOP_HEADERS: [&str; 6] = ["Connection", "Keep-Alive", "Proxy-Connection", "TE", "Upgrade", "Proxy-Authenticate"];
+
+pub fn remove_header(headers: &mut HashMap<String, String>, name: &str) {
+    headers.retain(|key, _| !key.eq_ignore_ascii_case(name));
+}
+
+/// Splits `path?query` into its two halves; the query is empty when there is none.
+pub fn split_query(uri: &str) -> (&str, &str) {
+    return uri.split_once("?").unwrap_or((uri, ""));
+}
+
+/// Decodes `%XX` escapes and, for query strings, `+` as a space.
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut res: Vec<u8> = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'+', _) => res.push(b' '),
+            (b'%', Some(byte)) => {
+                res.push(byte);
+                i += 2;
+            },
+            (byte, _) => res.push(byte)
+        }
+        i += 1;
+    }
+    return String::from_utf8_lossy(&res).to_string();
+}
+
+/// Replaces `{name}` placeholders with the values captured by the route.
+pub fn fill_params(template: &str, params: &HashMap<String, String>) -> String {
+    let mut res = template.to_string();
+    params.iter().for_each(|(name, value)| {
+        res = res.replace(&format!("{{{}}}", name), value);
+    });
+    return res;
+}
+
+#[derive(Clone)]
 pub struct Request {
     pub uri: String,
     pub method: String,
+    pub version: String,
     pub headers: HashMap<String, String>,
-    pub body: String
+    pub body: Vec<u8>,
+    pub trailers: HashMap<String, String>,
+    /// Values captured by `:name` and `*name` segments of the matched route.
+    pub params: HashMap<String, String>
 }
 
 impl Request {
-    pub fn from_stream(mut stream:&TcpStream) -> Option<Self> {
-        let mut headers:HashMap<String, String> = HashMap::new();
-
-        let mut buf = BufReader::new(&mut stream);
-        let mut data: String = String::new();
-        match buf.read_line(&mut data) {
-            Ok(_) => (),
-            Err(_) => return None
-        };
+    /// Reads the next request, body included, off a connection. The reader is kept
+    /// by the caller between calls, so bytes of pipelined requests are not lost.
+    pub fn from_stream<R: BufRead>(buf: &mut R) -> Option<Self> {
+        let mut req = Request::read_head(buf)?;
+        req.read_body(buf)?;
+        return Some(req);
+    }
 
+    /// Reads only the request line and headers, leaving the body on the connection
+    /// for `read_body` or for a component that streams it.
+    pub fn read_head<R: BufRead>(buf: &mut R) -> Option<Self> {
+        let mut data = read_head_line(buf)?;
+        // A stray CRLF between pipelined requests is allowed before the request line.
+        if data.trim().is_empty() {
+            data = read_head_line(buf)?;
+        }
         if data.trim().is_empty() {
             return None;
         }
 
-        loop {
-            let mut line:String = String::new();
-            buf.read_line(&mut line).unwrap();
+        let headers = read_headers(buf)?;
 
-            if line.trim().is_empty() {
-                break;
-            }
-            let (key, value) = line.trim().split_once(": ").unwrap();
-            headers.insert(key.to_string(), value.to_string());
-        }
-        
-        let mut body: String = String::new();
-        let content_length: u64 = headers
-            .get("Content-Length")
-            .unwrap_or(&"0".to_string())
-            .parse().unwrap();
-        buf.take(content_length).read_to_string(&mut body).unwrap();
-        
         let mut data = data.split_whitespace();
-        let method = data.nth(0).unwrap_or_default().to_string();
-        let uri = data.nth(0).unwrap_or_default().to_string();
+        let method = data.next().unwrap_or_default().to_string();
+        let uri = data.next().unwrap_or_default().to_string();
+        let version = data.next().unwrap_or("HTTP/1.0").to_string();
 
         return Some(Request {
-            uri: uri.to_string(),
+            uri,
+            method,
+            version,
             headers,
-            body,
-            method: method.to_string()
+            body: Vec::new(),
+            trailers: HashMap::new(),
+            params: HashMap::new()
         });
     }
 
-    pub fn stringify(&self) -> String {
-        let mut res = String::new();
-        res+=&self.method;
-        res+=" /";
-        res+=&self.uri;
-        res+=" ";
-        res+="HTTP/1.1\r\n";
-        self.headers.iter().for_each(|(key, value)| {
-            res+=key;
-            res+=": ";
-            res+=value;
-            res+="\r\n";
-        });
-        res+="\r\n";
-        res+=&self.body;
-        res+="\r\n";
+    pub fn read_body<R: BufRead>(&mut self, buf: &mut R) -> Option<()> {
+        (self.body, self.trailers) = read_body(buf, &self.headers)?;
+        return Some(());
+    }
+
+    /// Whether the client wants the connection kept open after this request.
+    /// HTTP/1.1 defaults to keep-alive, HTTP/1.0 has to ask for it.
+    pub fn keep_alive(&self) -> bool {
+        let connection = get_header(&self.headers, "Connection")
+            .map(|value| value.to_ascii_lowercase())
+            .unwrap_or_default();
+        if connection.split(",").any(|el| el.trim() == "close") {
+            return false;
+        }
+        if self.version == "HTTP/1.0" {
+            return connection.split(",").any(|el| el.trim() == "keep-alive");
+        }
+        return true;
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        let (_, query) = split_query(&self.uri);
+        return query
+            .split("&")
+            .filter_map(|pair| {
+                let (key, value) = pair.split_once("=").unwrap_or((pair, ""));
+                match percent_decode(key) == name {
+                    true => Some(percent_decode(value)),
+                    false => None
+                }
+            })
+            .next();
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        let cookies = get_header(&self.headers, "Cookie")?;
+        return cookies
+            .split(";")
+            .filter_map(|pair| pair.trim().split_once("="))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.trim_matches('"').to_string());
+    }
+
+    /// The protocol the client asks to switch to, e.g. `websocket`.
+    pub fn upgrade(&self) -> Option<&String> {
+        let connection = get_header(&self.headers, "Connection")?;
+        if !connection.split(",").any(|el| el.trim().eq_ignore_ascii_case("upgrade")) {
+            return None;
+        }
+        return get_header(&self.headers, "Upgrade");
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = Vec::new();
+        write_message(&mut res, &format!("{} /{} HTTP/1.1", self.method, self.uri), &self.headers, &self.body, &self.trailers);
+        return res;
+    }
+
+    /// Request line and headers as they are, for when the body is streamed after.
+    pub fn head_bytes(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = Vec::new();
+        write_head(&mut res, &format!("{} /{} HTTP/1.1", self.method, self.uri), &self.headers);
         return res;
     }
 }
@@ -103,70 +412,155 @@ pub struct Response {
     pub status: String,
     pub status_code: i64,
     pub headers: HashMap<String, String>,
-    pub body: String
+    pub body: Vec<u8>,
+    pub trailers: HashMap<String, String>
 }
 
 impl Response {
     pub fn new() -> Self {
-        return Response {status_code: 200, status: "Ok".to_string(), headers: HashMap::new(), body: String::new()}
+        return Response {status_code: 200, status: "Ok".to_string(), headers: HashMap::new(), body: Vec::new(), trailers: HashMap::new()}
+    }
+
+    pub fn bad_request() -> Self {
+        return Response { status_code: 400, status: "BadRequest".to_string(), headers: HashMap::new(), body: b"400 Bad request".to_vec(), trailers: HashMap::new() }
     }
 
     pub fn not_found() -> Self {
-        return Response { status_code: 404, status: "NotFound".to_string(), headers: HashMap::new(), body: "404 Not found".to_string() }
-    }
-
-    pub fn into_string(&self) -> String {
-        let res = format!(
-            "HTTP/1.1 {} {}\r\n{}\r\n\r\n{}\r\n",
-            self.status_code,
-            self.status,
-            self.headers
-                .iter()
-                .map(|(key, value)| format!("{}: {}", key, value))
-                .collect::<Vec<String>>()
-                .join("\r\n"),
-            self.body
-        );
+        return Response { status_code: 404, status: "NotFound".to_string(), headers: HashMap::new(), body: b"404 Not found".to_vec(), trailers: HashMap::new() }
+    }
+
+    pub fn method_not_allowed(allowed: &[String]) -> Self {
+        let mut headers: HashMap<String, String> = HashMap::new();
+        headers.insert("Allow".to_string(), allowed.join(", "));
+        return Response { status_code: 405, status: "MethodNotAllowed".to_string(), headers, body: b"405 Method not allowed".to_vec(), trailers: HashMap::new() }
+    }
+
+    pub fn bad_gateway() -> Self {
+        return Response { status_code: 502, status: "BadGateway".to_string(), headers: HashMap::new(), body: b"502 Bad gateway".to_vec(), trailers: HashMap::new() }
+    }
+
+    pub fn service_unavailable() -> Self {
+        return Response { status_code: 503, status: "ServiceUnavailable".to_string(), headers: HashMap::new(), body: b"503 Service unavailable".to_vec(), trailers: HashMap::new() }
+    }
+
+    pub fn server_error() -> Self {
+        return Response { status_code: 500, status: "ServerError".to_string(), headers: HashMap::new(), body: Vec::new(), trailers: HashMap::new() }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = Vec::new();
+        write_message(&mut res, &format!("HTTP/1.1 {} {}", self.status_code, self.status), &self.headers, &self.body, &self.trailers);
         return res;
     }
 
-    pub fn from_stream(mut stream:TcpStream) -> Option<Self> {
-        let mut buf = BufReader::new(&mut stream);
-        let mut data = String::new();
-        match buf.read_line(&mut data) {
-            Ok(_) => {},
-            Err(_) => return None
-        };
-
-        let mut data = data.trim().split_whitespace();
-        let status_code = data.nth(1).unwrap();
-        let status = data.nth(0).unwrap();
-        let mut headers:HashMap<String, String> = HashMap::new();
-        loop {
-            let mut line = String::new();
-            buf.read_line(&mut line).unwrap();
-            let line = line.trim();
+    /// Status line and headers as they are, for when the body is streamed after.
+    pub fn head_bytes(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = Vec::new();
+        write_head(&mut res, &format!("HTTP/1.1 {} {}", self.status_code, self.status), &self.headers);
+        return res;
+    }
 
-            if line.is_empty() {
-                break;
-            }
-            let (key, value) = line.split_once(": ").unwrap();
-            headers.insert(key.to_string(), value.to_string());
-        }
-        let mut body = String::new();
-        let content_length: u64 = headers
-            .get("Content-Length")
-            .unwrap_or(&"0".to_string())
-            .parse()
-            .unwrap();
-        buf.take(content_length).read_to_string(&mut body).unwrap();
+    /// The answer to a `HEAD` request: the head `to_bytes` would send, without the body.
+    pub fn head_request_bytes(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = Vec::new();
+        write_head(&mut res, &format!("HTTP/1.1 {} {}", self.status_code, self.status), &framed_headers(&self.headers, &self.body));
+        return res;
+    }
+
+    /// A response with neither length nor chunking runs until upstream closes.
+    pub fn is_close_delimited(&self) -> bool {
+        return has_body(self.status_code) && !is_chunked(&self.headers) && get_header(&self.headers, "Content-Length").is_none();
+    }
+
+    pub fn read_head<R: BufRead>(buf: &mut R) -> Option<Self> {
+        let data = read_head_line(buf)?;
+
+        let mut data = data.trim().splitn(3, ' ');
+        let status_code = data.nth(1)?.parse().ok()?;
+        let status = data.next().unwrap_or_default().to_string();
+        let headers = read_headers(buf)?;
 
         return Some(Response {
-            status_code: status_code.parse().unwrap(),
-            status: status.to_string(),
+            status_code,
+            status,
             headers,
-            body
+            body: Vec::new(),
+            trailers: HashMap::new()
         });
     }
+
+    pub fn from_stream<S: Read>(mut stream: S) -> Option<Self> {
+        let mut buf = BufReader::new(&mut stream);
+        let mut res = Response::read_head(&mut buf)?;
+        if res.is_close_delimited() {
+            buf.read_to_end(&mut res.body).ok()?;
+        } else if has_body(res.status_code) {
+            (res.body, res.trailers) = read_body(&mut buf, &res.headers)?;
+        }
+        return Some(res);
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_chunked_joins_chunks_and_keeps_trailers() {
+        let mut input: &[u8] = b"4;name=value\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\n";
+        let (body, trailers) = read_chunked(&mut input).unwrap();
+        assert_eq!(body, b"Wikipedia");
+        assert_eq!(trailers.get("Expires").map(|value| value.as_str()), Some("never"));
+    }
+
+    #[test]
+    fn read_chunked_rejects_a_chunk_without_crlf() {
+        let mut input: &[u8] = b"4\r\nWikiXX0\r\n\r\n";
+        assert!(read_chunked(&mut input).is_none());
+    }
+
+    #[test]
+    fn read_chunked_rejects_sizes_larger_than_the_data() {
+        let mut input: &[u8] = b"ffffffffffff\r\nabc\r\n0\r\n\r\n";
+        assert!(read_chunked(&mut input).is_none());
+        let mut input: &[u8] = b"ffffffffffffffffffff\r\nabc\r\n0\r\n\r\n";
+        assert!(read_chunked(&mut input).is_none());
+    }
+
+    #[test]
+    fn write_chunked_round_trips() {
+        let mut trailers: HashMap<String, String> = HashMap::new();
+        trailers.insert("Checksum".to_string(), "abc".to_string());
+        let mut encoded: Vec<u8> = Vec::new();
+        write_chunked(&mut encoded, b"hello", &trailers);
+        assert_eq!(encoded, b"5\r\nhello\r\n0\r\nChecksum: abc\r\n\r\n");
+        let (body, decoded) = read_chunked(&mut encoded.as_slice()).unwrap();
+        assert_eq!(body, b"hello");
+        assert_eq!(decoded, trailers);
+    }
+
+    #[test]
+    fn write_chunked_empty_body_is_only_the_last_chunk() {
+        let mut encoded: Vec<u8> = Vec::new();
+        write_chunked(&mut encoded, b"", &HashMap::new());
+        assert_eq!(encoded, b"0\r\n\r\n");
+    }
+
+    #[test]
+    fn head_request_bytes_keeps_the_length_and_drops_the_body() {
+        let mut res = Response::new();
+        res.body = b"hello world\n".to_vec();
+        let head = String::from_utf8(res.head_request_bytes()).unwrap();
+        assert!(head.contains("Content-Length: 12\r\n"));
+        assert!(head.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn malformed_heads_and_lengths_are_rejected() {
+        assert!(Request::read_head(&mut "GET / HTTP/1.1\r\nno colon here\r\n\r\n".as_bytes()).is_none());
+        let req = Request::read_head(&mut "POST / HTTP/1.1\r\nContent-Length: ten\r\n\r\n".as_bytes()).unwrap();
+        assert!(!has_valid_framing(&req.headers));
+        let req = Request::read_head(&mut "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n".as_bytes()).unwrap();
+        assert!(has_valid_framing(&req.headers));
+    }
+}
@@ -0,0 +1,150 @@
+use std::{collections::HashMap, fs::{self, OpenOptions}, io::{self, prelude::*}, sync::Mutex, time::{SystemTime, UNIX_EPOCH}};
+
+use serde_json::{Map, Value, json};
+
+use crate::structs::http::{Request, Response, split_query, remove_header, HOP_BY_HOP_HEADERS};
+
+/// Where recordings go when `record` is `true` instead of a file name.
+pub const DEFAULT_RECORDING_FILE: &str = "requests.jsonl";
+
+/// Headers carrying credentials, left out of recordings unless asked for.
+const SECRET_HEADERS: [&str; 4] = ["Authorization", "Proxy-Authorization", "Cookie", "Set-Cookie"];
+
+/// Serializes appends, so lines from concurrent requests never interleave.
+static APPEND_LOCK: Mutex<()> = Mutex::new(());
+
+/// One proxied exchange, stored as a JSON line. `path` is the uri the
+/// component saw, so it is relative to the route the proxy is mounted on.
+pub struct Recording {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub request_headers: HashMap<String, String>,
+    pub request_body: Vec<u8>,
+    pub status_code: i64,
+    pub status: String,
+    pub response_headers: HashMap<String, String>,
+    pub response_body: Vec<u8>
+}
+
+/// Text bodies are kept readable, anything else is stored as hex.
+fn body_to_json(body: &[u8], object: &mut Map<String, Value>) {
+    match std::str::from_utf8(body) {
+        Ok(text) => object.insert("body".to_string(), Value::String(text.to_string())),
+        Err(_) => object.insert("body_hex".to_string(), Value::String(body.iter().map(|byte| format!("{:02x}", byte)).collect()))
+    };
+}
+
+fn body_from_json(object: &Value) -> Option<Vec<u8>> {
+    if let Some(hex) = object.get("body_hex") {
+        let hex = hex.as_str()?.as_bytes();
+        return hex.chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+            .collect();
+    }
+    return match object.get("body") {
+        Some(body) => Some(body.as_str()?.as_bytes().to_vec()),
+        None => Some(Vec::new())
+    };
+}
+
+fn headers_from_json(object: &Value) -> Option<HashMap<String, String>> {
+    return match object.get("headers") {
+        Some(headers) => headers.as_object()?.iter()
+            .map(|(key, value)| Some((key.to_string(), value.as_str()?.to_string())))
+            .collect(),
+        None => Some(HashMap::new())
+    };
+}
+
+impl Recording {
+    pub fn new(req: &Request, res: &Response, keep_secrets: bool) -> Self {
+        let (path, query) = split_query(&req.uri);
+        let mut request_headers = req.headers.clone();
+        let mut response_headers = res.headers.clone();
+        // Bodies are stored whole, so framing and connection headers no longer apply.
+        for headers in [&mut request_headers, &mut response_headers] {
+            HOP_BY_HOP_HEADERS.iter().for_each(|name| remove_header(headers, name));
+            remove_header(headers, "Transfer-Encoding");
+            remove_header(headers, "Content-Length");
+            if !keep_secrets {
+                SECRET_HEADERS.iter().for_each(|name| remove_header(headers, name));
+            }
+        }
+        return Recording {
+            method: req.method.clone(),
+            path: path.to_string(),
+            query: query.to_string(),
+            request_headers,
+            request_body: req.body.clone(),
+            status_code: res.status_code,
+            status: res.status.clone(),
+            response_headers,
+            response_body: res.body.clone()
+        };
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut request = Map::new();
+        request.insert("headers".to_string(), json!(self.request_headers));
+        body_to_json(&self.request_body, &mut request);
+        let mut response = Map::new();
+        response.insert("status".to_string(), json!(self.status_code));
+        response.insert("status_text".to_string(), json!(self.status));
+        response.insert("headers".to_string(), json!(self.response_headers));
+        body_to_json(&self.response_body, &mut response);
+        let recorded_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default();
+        return json!({
+            "method": self.method,
+            "path": self.path,
+            "query": self.query,
+            "recorded_at": recorded_at,
+            "request": request,
+            "response": response
+        });
+    }
+
+    pub fn from_json(value: &Value) -> Option<Self> {
+        let request = value.get("request")?;
+        let response = value.get("response")?;
+        return Some(Recording {
+            method: value.get("method")?.as_str()?.to_string(),
+            path: value.get("path")?.as_str()?.to_string(),
+            query: value.get("query").and_then(|query| query.as_str()).unwrap_or_default().to_string(),
+            request_headers: headers_from_json(request)?,
+            request_body: body_from_json(request)?,
+            status_code: response.get("status")?.as_i64()?,
+            status: response.get("status_text").and_then(|status| status.as_str()).unwrap_or("Ok").to_string(),
+            response_headers: headers_from_json(response)?,
+            response_body: body_from_json(response)?
+        });
+    }
+
+    pub fn to_response(&self) -> Response {
+        let mut res = Response::new();
+        res.status_code = self.status_code;
+        res.status = self.status.clone();
+        res.headers = self.response_headers.clone();
+        res.body = self.response_body.clone();
+        return res;
+    }
+
+    /// Adds the recording as the last line of `file`.
+    pub fn append(&self, file: &str) -> io::Result<()> {
+        let mut line = self.to_json().to_string();
+        line.push('\n');
+        let _lock = APPEND_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let mut file = OpenOptions::new().create(true).append(true).open(file)?;
+        return file.write_all(line.as_bytes());
+    }
+
+    /// Reads every recording in `file`, oldest first. Lines that are not
+    /// recordings are skipped, so the file can be edited by hand.
+    pub fn load(file: &str) -> io::Result<Vec<Self>> {
+        let content = fs::read_to_string(file)?;
+        return Ok(content.lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .filter_map(|value| Recording::from_json(&value))
+            .collect());
+    }
+}
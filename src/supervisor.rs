@@ -0,0 +1,220 @@
+use std::{collections::HashMap, io::{BufRead, BufReader, Read}, process::{Child, Command, Stdio}, thread, time::{Duration, Instant}};
+use std::sync::{Arc, LazyLock, Mutex, atomic::{AtomicBool, Ordering}};
+
+use crate::structs::data::{GlobalState, ProcessSpec, Restart, Service};
+
+/// What is needed to stop a supervised service: the flag its supervisor checks
+/// before every (re)start and the process currently running, if any.
+#[derive(Default)]
+struct Handle {
+    stop: AtomicBool,
+    child: Mutex<Option<Child>>
+}
+
+/// Supervised services by name, so reloads and shutdown can stop them and a
+/// service is never supervised twice.
+static SUPERVISED: LazyLock<Mutex<HashMap<String, Arc<Handle>>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn supervised() -> std::sync::MutexGuard<'static, HashMap<String, Arc<Handle>>> {
+    return SUPERVISED.lock().unwrap_or_else(|err| err.into_inner());
+}
+
+/// Asks a process and, on Unix, everything it started to exit.
+fn terminate(child: &mut Child) {
+    #[cfg(unix)]
+    {
+        // The child leads its own process group, so `sh -c` grandchildren go too.
+        unsafe { libc::kill(-(child.id() as i32), libc::SIGTERM) };
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = child.kill();
+    }
+}
+
+/// Starts the process of every service with a `command`, each once the services
+/// it depends on are ready, and restarts them according to their policy.
+pub fn run_services(global: &GlobalState) {
+    for name in global.services.keys() {
+        start_service(name, global);
+    }
+}
+
+/// Starts the process of one service, if it has a `command` and is not supervised yet.
+pub fn start_service(name: &str, global: &GlobalState) {
+    let service = &global.services[name];
+    let spec = match service.process() {
+        Some(spec) => spec.clone(),
+        None => return
+    };
+    let handle = Arc::new(Handle::default());
+    {
+        let mut supervised = supervised();
+        if supervised.contains_key(name) {
+            return;
+        }
+        supervised.insert(name.to_string(), Arc::clone(&handle));
+    }
+    let width = global.services.iter()
+        .filter(|(_, service)| service.process().is_some())
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or_default();
+    let dependencies: Vec<(String, Service)> = service.depends_on().iter()
+        .map(|dependency| (dependency.clone(), global.services[dependency].clone()))
+        .collect();
+    let name = name.to_string();
+    let prefix = format!("{:width$} | ", name, width = width);
+    thread::spawn(move || {
+        wait_for(&name, &dependencies, &handle);
+        supervise(&name, &prefix, &spec, &handle);
+        let mut supervised = supervised();
+        if supervised.get(&name).is_some_and(|current| Arc::ptr_eq(current, &handle)) {
+            supervised.remove(&name);
+        }
+    });
+}
+
+/// Stops supervising a service and terminates its process. Returns at once,
+/// the supervisor notices the exit and does not restart it.
+pub fn stop_service(name: &str) {
+    let handle = match supervised().remove(name) {
+        Some(handle) => handle,
+        None => return
+    };
+    handle.stop.store(true, Ordering::SeqCst);
+    if let Some(child) = handle.child.lock().unwrap_or_else(|err| err.into_inner()).as_mut() {
+        terminate(child);
+    }
+    println!("Service {} stopped", name);
+}
+
+/// Terminates every supervised process, for when DevSync exits.
+pub fn stop_all_services() {
+    let names: Vec<String> = supervised().keys().cloned().collect();
+    for name in names {
+        stop_service(&name);
+    }
+}
+
+fn wait_for(name: &str, dependencies: &[(String, Service)], handle: &Handle) {
+    for (dependency, service) in dependencies {
+        let started = Instant::now();
+        let mut announced = false;
+        while !service.is_ready() {
+            if handle.stop.load(Ordering::SeqCst) {
+                return;
+            }
+            if !announced && started.elapsed() > Duration::from_secs(5) {
+                println!("Service {} is waiting for {}", name, dependency);
+                announced = true;
+            }
+            thread::sleep(Duration::from_millis(250));
+        }
+    }
+}
+
+/// Copies a child's output to ours line by line, each line behind `prefix`.
+fn forward<R: Read + Send + 'static>(output: R, prefix: String) {
+    thread::spawn(move || {
+        let mut output = BufReader::new(output);
+        let mut line: Vec<u8> = Vec::new();
+        loop {
+            line.clear();
+            match output.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => println!("{}{}", prefix, String::from_utf8_lossy(&line).trim_end())
+            }
+        }
+    });
+}
+
+/// Waits for the child in `handle` to exit. It is polled rather than waited on,
+/// so `stop_service` can reach it meanwhile.
+fn wait_child(handle: &Handle) -> std::io::Result<std::process::ExitStatus> {
+    loop {
+        if let Some(child) = handle.child.lock().unwrap_or_else(|err| err.into_inner()).as_mut() {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn supervise(name: &str, prefix: &str, spec: &ProcessSpec, handle: &Handle) {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let mut command = Command::new(&spec.command[0]);
+        command
+            .args(&spec.command[1..])
+            .envs(&spec.env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(cwd) = &spec.cwd {
+            command.current_dir(cwd);
+        }
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let started = Instant::now();
+        let spawned = {
+            // Checked under the lock `stop_service` takes, so a stopped service never starts again.
+            let mut current = handle.child.lock().unwrap_or_else(|err| err.into_inner());
+            if handle.stop.load(Ordering::SeqCst) {
+                return;
+            }
+            command.spawn().map(|mut child| {
+                println!("Service {} started (pid {})", name, child.id());
+                if let Some(stdout) = child.stdout.take() {
+                    forward(stdout, prefix.to_string());
+                }
+                if let Some(stderr) = child.stderr.take() {
+                    forward(stderr, prefix.to_string());
+                }
+                *current = Some(child);
+            })
+        };
+        let success = match spawned {
+            Ok(()) => {
+                let status = wait_child(handle);
+                handle.child.lock().unwrap_or_else(|err| err.into_inner()).take();
+                if handle.stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                match status {
+                    Ok(status) => {
+                        println!("Service {} exited with {}", name, status);
+                        status.success()
+                    },
+                    Err(err) => {
+                        println!("Lost service {}: {}", name, err);
+                        false
+                    }
+                }
+            },
+            Err(err) => {
+                println!("Could not start service {}: {}", name, err);
+                false
+            }
+        };
+        let restart = match spec.restart {
+            Restart::Never => false,
+            Restart::OnFailure => !success,
+            Restart::Always => true
+        };
+        if !restart {
+            return;
+        }
+        // A process that keeps crashing right away is restarted less and less often.
+        if started.elapsed() > Duration::from_secs(10) {
+            backoff = Duration::from_secs(1);
+        }
+        println!("Restarting service {} in {}s", name, backoff.as_secs());
+        thread::sleep(backoff);
+        if handle.stop.load(Ordering::SeqCst) {
+            return;
+        }
+        backoff = (backoff * 2).min(Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,255 @@
+use std::{fs, io::{self, prelude::*}, net::{Shutdown, TcpStream}, path::Path, sync::{Arc, Mutex}, time::Duration};
+
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, Issuer, KeyPair, KeyUsagePurpose};
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, ServerConfig, ServerConnection, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature}
+};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject};
+
+use crate::structs::{connection::Stream, data::Tls};
+
+/// A TLS session over a TCP socket. Clones share the session, so one thread can
+/// block reading while another writes, the same way a cloned `TcpStream` works.
+pub struct TlsStream {
+    conn: Arc<Mutex<rustls::Connection>>,
+    sock: TcpStream
+}
+
+impl TlsStream {
+    fn flush_tls(conn: &mut rustls::Connection, mut sock: &TcpStream) -> io::Result<()> {
+        while conn.wants_write() {
+            conn.write_tls(&mut sock)?;
+        }
+        return Ok(());
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut conn = self.conn.lock().unwrap();
+                match conn.reader().read(buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {},
+                    result => return result
+                }
+            }
+            // The lock is not held while waiting on the socket, so writers are not blocked.
+            let mut raw = [0u8; 16 * 1024];
+            let n = self.sock.read(&mut raw)?;
+            let mut conn = self.conn.lock().unwrap();
+            let mut data = &raw[..n];
+            loop {
+                conn.read_tls(&mut data)?;
+                conn.process_new_packets()
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                if data.is_empty() {
+                    break;
+                }
+            }
+            TlsStream::flush_tls(&mut conn, &self.sock)?;
+            if n == 0 {
+                return match conn.reader().read(buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(0),
+                    result => result
+                };
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let n = conn.writer().write(buf)?;
+        TlsStream::flush_tls(&mut conn, &self.sock)?;
+        return Ok(n);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.writer().flush()?;
+        return TlsStream::flush_tls(&mut conn, &self.sock);
+    }
+}
+
+impl Stream for TlsStream {
+    fn try_clone_stream(&self) -> io::Result<Box<dyn Stream>> {
+        return Ok(Box::new(TlsStream { conn: Arc::clone(&self.conn), sock: self.sock.try_clone()? }));
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            let mut conn = self.conn.lock().unwrap();
+            conn.send_close_notify();
+            let _ = TlsStream::flush_tls(&mut conn, &self.sock);
+        }
+        return self.sock.shutdown(how);
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        return self.sock.set_read_timeout(timeout);
+    }
+}
+
+/// Runs the server side of the handshake on a freshly accepted socket.
+pub fn accept(config: Arc<ServerConfig>, mut sock: TcpStream) -> io::Result<TlsStream> {
+    let mut conn = ServerConnection::new(config)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut sock)?;
+    }
+    return Ok(TlsStream { conn: Arc::new(Mutex::new(rustls::Connection::Server(conn))), sock });
+}
+
+/// Runs the client side of the handshake against an upstream, sending `server_name` as SNI.
+pub fn connect(config: Arc<ClientConfig>, server_name: &str, mut sock: TcpStream) -> io::Result<TlsStream> {
+    let server_name = ServerName::try_from(server_name.to_string())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut conn = ClientConnection::new(config, server_name)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut sock)?;
+    }
+    return Ok(TlsStream { conn: Arc::new(Mutex::new(rustls::Connection::Client(conn))), sock });
+}
+
+/// Accepts any upstream certificate. Handshake signatures are still checked, so
+/// this only gives up on knowing who the upstream is, not on the encryption.
+#[derive(Debug)]
+struct InsecureVerifier {
+    provider: Arc<CryptoProvider>
+}
+
+impl ServerCertVerifier for InsecureVerifier {
+    fn verify_server_cert(&self, _end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        return Ok(ServerCertVerified::assertion());
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        return verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms);
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        return verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms);
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        return self.provider.signature_verification_algorithms.supported_schemes();
+    }
+}
+
+/// Builds the config for talking to an HTTPS upstream. Certificates are checked
+/// against the public web roots plus the optional `ca` bundle, unless `insecure`.
+pub fn client_config(ca: Option<&str>, insecure: bool) -> Result<Arc<ClientConfig>, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?;
+    let mut config = if insecure {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(InsecureVerifier { provider }))
+            .with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+        if let Some(ca) = ca {
+            let certs = match CertificateDer::pem_file_iter(ca) {
+                Ok(certs) => certs.collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| format!("CA bundle {} is not valid PEM", ca))?,
+                Err(_) => return Err(format!("File {} does not exist", ca))
+            };
+            for cert in certs {
+                roots.add(cert).map_err(|err| format!("Bad certificate in {}: {}", ca, err))?;
+            }
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    return Ok(Arc::new(config));
+}
+
+fn dev_ca_params() -> Result<CertificateParams, String> {
+    let mut params = CertificateParams::new(Vec::<String>::new()).map_err(|err| err.to_string())?;
+    // It only ever signs leaf certificates, never another CA.
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.distinguished_name.push(DnType::CommonName, "DevSync Development CA");
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign, KeyUsagePurpose::DigitalSignature];
+    return Ok(params);
+}
+
+/// Writes a file only its owner can read, for private keys.
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    // Mode is only applied to new files, so an old one is replaced rather than overwritten.
+    let _ = fs::remove_file(path);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    return options.open(path)?.write_all(contents.as_bytes());
+}
+
+/// Loads the dev CA key from `ca_dir`, creating the CA on first use. The CA
+/// certificate is kept next to it so it only has to be trusted once.
+fn load_or_create_ca(ca_dir: &str) -> Result<Issuer<'static, KeyPair>, String> {
+    let cert_path = Path::new(ca_dir).join("ca.pem");
+    let key_path = Path::new(ca_dir).join("ca-key.pem");
+    let params = dev_ca_params()?;
+    if cert_path.exists() && key_path.exists() {
+        let key = match fs::read_to_string(&key_path) {
+            Ok(key) => KeyPair::from_pem(&key).map_err(|err| format!("Bad dev CA key {}: {}", key_path.display(), err))?,
+            Err(_) => return Err(format!("Could not read dev CA key {}", key_path.display()))
+        };
+        return Ok(Issuer::new(params, key));
+    }
+    let key = KeyPair::generate().map_err(|err| err.to_string())?;
+    let cert = params.self_signed(&key).map_err(|err| err.to_string())?;
+    if fs::create_dir_all(ca_dir).is_err()
+        || fs::write(&cert_path, cert.pem()).is_err()
+        || write_private(&key_path, &key.serialize_pem()).is_err() {
+        return Err(format!("Could not write dev CA to {}", ca_dir));
+    }
+    println!("Generated dev CA {}, add it to your trusted roots", cert_path.display());
+    return Ok(Issuer::new(params, key));
+}
+
+fn generate_certificate(tls: &Tls) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), String> {
+    let issuer = load_or_create_ca(&tls.ca_dir)?;
+    let mut params = CertificateParams::new(tls.hostnames.clone())
+        .map_err(|err| format!("Bad TLS hostname: {}", err))?;
+    if let Some(hostname) = tls.hostnames.first() {
+        params.distinguished_name.push(DnType::CommonName, hostname.as_str());
+    }
+    let key = KeyPair::generate().map_err(|err| err.to_string())?;
+    let cert = params.signed_by(&key, &issuer).map_err(|err| err.to_string())?;
+    return Ok((vec![cert.der().clone()], PrivateKeyDer::Pkcs8(key.serialize_der().into())));
+}
+
+fn load_certificate(cert: &str, key: &str) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), String> {
+    let certs = match CertificateDer::pem_file_iter(cert) {
+        Ok(certs) => certs.collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("Certificate {} is not valid PEM", cert))?,
+        Err(_) => return Err(format!("File {} does not exist", cert))
+    };
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|_| format!("Could not read private key from {}", key))?;
+    return Ok((certs, key));
+}
+
+pub fn server_config(tls: &Tls) -> Result<Arc<ServerConfig>, String> {
+    let (certs, key) = match (&tls.cert, &tls.key) {
+        (Some(cert), Some(key)) => load_certificate(cert, key)?,
+        _ => generate_certificate(tls)?
+    };
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| format!("Bad TLS certificate: {}", err))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    return Ok(Arc::new(config));
+}